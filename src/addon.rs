@@ -10,11 +10,17 @@ pub fn set_override_env_prefix(prefix: &str) {
     drop(lock);
 }
 
-pub fn load_envs_from_os() -> Vec<String> {
+/// Envs from host those names have the prefix given on command line, or `default_prefix` from
+/// config if not given.
+pub fn load_envs_from_os(default_prefix: Option<&str>) -> Vec<String> {
     let lock = ADDON_ENV_PREFIX.lock().unwrap();
-    let prefix = (*lock).clone();
+    let mut prefix = (*lock).clone();
     drop(lock);
 
+    if prefix.is_empty() {
+        prefix = default_prefix.unwrap_or_default().to_string();
+    }
+
     if prefix.is_empty() {
         return vec![];
     }
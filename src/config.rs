@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use racros::AutoDebug;
+use serde::Deserialize;
+
+use crate::constants::{PROJECT_CONFIG_FILE, USER_CONFIG_DIR, USER_CONFIG_FILE};
+use crate::{box_error, debug_println};
+
+/// Defaults of fbh options, loaded from user config and project config.
+///
+/// Precedence from high to low: command line, project `fbh.toml`, user config, built-in defaults.
+/// Arg lists are concatenated in the same order, user config first.
+/// Relative paths are relative to project root dir, see `resolve_path`. Configured dirs keep a sub
+/// dir for each manifest, named the same as its build tree.
+#[derive(AutoDebug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Manifest to use instead of the *.Devel.json found in build-aux, same as --manifest.
+    pub manifest: Option<PathBuf>,
+    /// Directory keeping build trees of manifests, default .flatpak.
+    #[serde(rename = "build-dir")]
+    pub build_dir: Option<PathBuf>,
+    /// Build init dir, default repo in build tree.
+    #[serde(rename = "repo-dir")]
+    pub repo_dir: Option<PathBuf>,
+    /// flatpak-builder state dir with downloads and ccache, default flatpak-builder in build tree.
+    #[serde(rename = "state-dir")]
+    pub state_dir: Option<PathBuf>,
+    /// Build dir of the app module build system, default _build in build tree.
+    #[serde(rename = "build-system-dir")]
+    pub build_system_dir: Option<PathBuf>,
+    /// App module built in tree, same as --module.
+    pub module: Option<String>,
+    /// Same as --override-env-prefix.
+    #[serde(rename = "override-env-prefix")]
+    pub override_env_prefix: Option<String>,
+    /// Extra args passed to `flatpak build` in build steps.
+    #[serde(rename = "build-args", default)]
+    pub build_args: Vec<String>,
+    /// Extra args passed to `flatpak build` when running.
+    #[serde(rename = "run-args", default)]
+    pub run_args: Vec<String>,
+    /// Parallel jobs in build, default logical cpu count.
+    pub jobs: Option<usize>,
+    /// Same as --buildtype.
+    pub buildtype: Option<String>,
+    /// Profile used when --profile is not given.
+    pub profile: Option<String>,
+    /// Named build profiles, selected by --profile.
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+/// Named build profile, applied on top of the manifest.
+///
+/// Each profile builds in its own build system dir, so that switching profiles does not force a
+/// full reconfigure.
+#[derive(AutoDebug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Appended to config-opts of the app module.
+    #[serde(rename = "config-opts", default)]
+    pub config_opts: Vec<String>,
+    /// Envs set in build steps of the app module.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Appended to CFLAGS and CXXFLAGS of the app module, after cflags and cxxflags in manifest
+    /// build-options, overridden by the same envs in `env`.
+    pub cflags: Option<String>,
+    /// Overrides buildtype in config, --buildtype still takes precedence.
+    pub buildtype: Option<String>,
+}
+
+impl Config {
+    /// Load user config then project config in `root_dir`, missing files are skipped.
+    pub fn load(root_dir: &Path) -> Result<Config, Box<dyn Error>> {
+        let mut config = Config::default();
+        if let Some(v) = dirs::config_dir() {
+            config = config.merge(Config::load_file(
+                &v.join(USER_CONFIG_DIR).join(USER_CONFIG_FILE),
+            )?);
+        }
+        config = config.merge(Config::load_file(&root_dir.join(PROJECT_CONFIG_FILE))?);
+        debug_println!("config: {:#?}", config);
+        Ok(config)
+    }
+
+    fn load_file(path: &Path) -> Result<Config, Box<dyn Error>> {
+        if !path.is_file() {
+            return Ok(Config::default());
+        }
+        debug_println!("load config {}", path.to_str().unwrap());
+        match toml::from_str(read_to_string(path)?.as_str()) {
+            Ok(v) => Ok(v),
+            Err(e) => box_error!("invalid config {}: {}", path.to_str().unwrap(), e),
+        }
+    }
+
+    /// Values in `other` override those in self, arg lists are appended.
+    /// Profiles with the same name in `other` replace those in self.
+    fn merge(mut self, other: Config) -> Config {
+        self.build_args.extend(other.build_args);
+        self.run_args.extend(other.run_args);
+        self.profiles.extend(other.profiles);
+        Config {
+            manifest: other.manifest.or(self.manifest),
+            module: other.module.or(self.module),
+            build_dir: other.build_dir.or(self.build_dir),
+            repo_dir: other.repo_dir.or(self.repo_dir),
+            state_dir: other.state_dir.or(self.state_dir),
+            build_system_dir: other.build_system_dir.or(self.build_system_dir),
+            override_env_prefix: other.override_env_prefix.or(self.override_env_prefix),
+            build_args: self.build_args,
+            run_args: self.run_args,
+            jobs: other.jobs.or(self.jobs),
+            buildtype: other.buildtype.or(self.buildtype),
+            profile: other.profile.or(self.profile),
+            profiles: self.profiles,
+        }
+    }
+}
+
+/// Resolve a path in config, `~/` is expanded to home dir, relative paths are relative to
+/// `root_dir`.
+pub fn resolve_path(root_dir: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(v) => dirs::home_dir().expect("failed to get home dir").join(v),
+        Err(_) => root_dir.join(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &str) -> Config {
+        toml::from_str(data).unwrap()
+    }
+
+    #[test]
+    fn merge_overrides_values() {
+        let config = parse(
+            r#"
+            manifest = "user.json"
+            jobs = 2
+            buildtype = "debug"
+            "#,
+        )
+        .merge(parse(
+            r#"
+            manifest = "project.json"
+            profile = "dev"
+            "#,
+        ));
+        assert_eq!(config.manifest, Some(PathBuf::from("project.json")));
+        assert_eq!(config.jobs, Some(2));
+        assert_eq!(config.buildtype.as_deref(), Some("debug"));
+        assert_eq!(config.profile.as_deref(), Some("dev"));
+    }
+
+    #[test]
+    fn merge_appends_args() {
+        let config = parse(r#"build-args = ["--a"]"#).merge(parse(
+            r#"
+            build-args = ["--b"]
+            run-args = ["--c"]
+            "#,
+        ));
+        assert_eq!(config.build_args, vec!["--a", "--b"]);
+        assert_eq!(config.run_args, vec!["--c"]);
+    }
+
+    #[test]
+    fn merge_replaces_profiles() {
+        let config = parse(
+            r#"
+            [profiles.dev]
+            buildtype = "debug"
+            config-opts = ["-Da=1"]
+            [profiles.release]
+            buildtype = "release"
+            "#,
+        )
+        .merge(parse(
+            r#"
+            [profiles.dev]
+            cflags = "-O0"
+            "#,
+        ));
+        assert_eq!(config.profiles.len(), 2);
+        let dev = &config.profiles["dev"];
+        assert_eq!(dev.buildtype, None);
+        assert!(dev.config_opts.is_empty());
+        assert_eq!(dev.cflags.as_deref(), Some("-O0"));
+        assert_eq!(
+            config.profiles["release"].buildtype.as_deref(),
+            Some("release")
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        assert!(toml::from_str::<Config>("unknown = 1").is_err());
+        assert!(toml::from_str::<Config>("[profiles.dev]\nunknown = 1").is_err());
+    }
+
+    #[test]
+    fn resolve_relative_and_home_paths() {
+        let root_dir = Path::new("/project");
+        assert_eq!(
+            resolve_path(root_dir, Path::new("build")),
+            PathBuf::from("/project/build")
+        );
+        assert_eq!(
+            resolve_path(root_dir, Path::new("/tmp/build")),
+            PathBuf::from("/tmp/build")
+        );
+        assert_eq!(
+            resolve_path(root_dir, Path::new("~/cache")),
+            dirs::home_dir().unwrap().join("cache")
+        );
+    }
+}
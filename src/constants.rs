@@ -1,7 +1,26 @@
 #[allow(dead_code)]
 
 pub static APP_LOG_VAR: &str = "FBH_LOG";
+pub static PROJECT_CONFIG_FILE: &str = "fbh.toml";
+/// User config is $XDG_CONFIG_HOME/fbh/config.toml.
+pub static USER_CONFIG_DIR: &str = "fbh";
+pub static USER_CONFIG_FILE: &str = "config.toml";
+/// Version of json message format event schema, bump on incompatible changes.
+pub static EVENT_SCHEMA_VERSION: u32 = 1;
 pub static BUILD_SYSTEM_BUILD_DIR: &str = "_build";
+pub static COMPILE_COMMANDS_FILE: &str = "compile_commands.json";
+pub static MESON_DEFAULT_BUILD_TYPE: &str = "debugoptimized";
+
+pub static STATE_FILE: &str = "fbh-state.json";
+pub static INIT_RECORD_FILE: &str = "fbh-init.json";
+pub static DEPENDENCIES_BUILT_FILE: &str = "fbh-dependencies-built";
+pub static RUN_PID_FILE: &str = "fbh-run.pid";
+pub static SIGNAL_FORWARD_TIMEOUT_MS: u64 = 5000;
+pub static WATCH_DEBOUNCE_MS: u64 = 300;
+pub static SANDBOX_SHELL: &str = "/bin/bash";
+
+pub static SDK_EXTENSION_POINT: &str = "org.freedesktop.Sdk.Extension";
+pub static SDK_EXTENSIONS_DIR: &str = "/usr/lib/sdk";
 
 pub static SYSTEM_FONTS_DIR: &str = "/usr/share/fonts";
 pub static SYSTEM_LOCAL_FONT_DIR: &str = "/usr/share/local/fonts";
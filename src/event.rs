@@ -0,0 +1,263 @@
+use std::io::Write;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::constants::EVENT_SCHEMA_VERSION;
+
+static MESSAGE_FORMAT_JSON: Mutex<bool> = Mutex::new(false);
+
+/// When serving, events are sent as progress notifications of this request.
+static PROGRESS_REQUEST_ID: Mutex<Option<Value>> = Mutex::new(None);
+
+lazy_static! {
+    // gcc, clang, blueprint-compiler: file:line:column: error: message
+    // valac: file:line.column-line.column: error: message
+    static ref DIAGNOSTIC_RE: Regex = Regex::new(
+        r"^(?P<file>[^:\s][^:]*):(?P<line>\d+)(?:[:.](?P<column>\d+))?(?:-\d+\.\d+)?:\s*(?:fatal )?(?P<severity>error|warning|note)(?:\[[^\]]+\])?:\s*(?P<message>.*)$"
+    )
+    .unwrap();
+    // rustc: error[E0308]: message
+    static ref RUSTC_HEADER_RE: Regex =
+        Regex::new(r"^(?P<severity>error|warning)(?:\[[^\]]+\])?: (?P<message>.*)$").unwrap();
+    // rustc:   --> file:line:column
+    static ref RUSTC_LOCATION_RE: Regex =
+        Regex::new(r"^\s*--> (?P<file>[^:]+):(?P<line>\d+):(?P<column>\d+)$").unwrap();
+}
+
+pub fn set_message_format_json(json: bool) {
+    let mut lock = MESSAGE_FORMAT_JSON.lock().unwrap();
+    *lock = json;
+    drop(lock);
+}
+
+pub fn is_message_format_json() -> bool {
+    *MESSAGE_FORMAT_JSON.lock().unwrap()
+}
+
+pub fn set_progress_request_id(id: Option<Value>) {
+    let mut lock = PROGRESS_REQUEST_ID.lock().unwrap();
+    *lock = id;
+    drop(lock);
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Events emitted in json message format, see "Message Format" in README for the schema.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    StepStarted {
+        step: usize,
+        total: usize,
+        command: Vec<String>,
+    },
+    StepFinished {
+        step: usize,
+        duration_ms: u128,
+        exit_code: Option<i32>,
+    },
+    Output {
+        step: Option<usize>,
+        stream: OutputStream,
+        line: String,
+    },
+    Diagnostic {
+        step: Option<usize>,
+        severity: String,
+        file: String,
+        line: u32,
+        column: Option<u32>,
+        message: String,
+    },
+    Warning {
+        message: String,
+    },
+    Result {
+        success: bool,
+        message: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct VersionedEvent<'a> {
+    version: u32,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Print `event` as one json line on stdout, or as a JSON-RPC progress notification when
+/// serving a request.
+pub fn emit(event: Event) {
+    let mut data = serde_json::to_value(VersionedEvent {
+        version: EVENT_SCHEMA_VERSION,
+        event: &event,
+    })
+    .unwrap();
+    if let Some(id) = PROGRESS_REQUEST_ID.lock().unwrap().clone() {
+        data["request_id"] = id;
+        data = json!({ "jsonrpc": "2.0", "method": "progress", "params": data });
+    }
+    let mut stdout = std::io::stdout().lock();
+    _ = writeln!(stdout, "{}", data);
+    _ = stdout.flush();
+}
+
+/// Print captured process output, as is or as output events in json message format.
+pub fn print_output(stream: OutputStream, data: &[u8]) {
+    if !is_message_format_json() {
+        match stream {
+            OutputStream::Stdout => _ = std::io::stdout().write_all(data),
+            OutputStream::Stderr => _ = std::io::stderr().write_all(data),
+        }
+        return;
+    }
+
+    String::from_utf8_lossy(data).lines().for_each(|x| {
+        emit(Event::Output {
+            step: None,
+            stream,
+            line: x.to_string(),
+        })
+    });
+}
+
+/// Handle output lines of a running step, parse diagnostics in json message format.
+pub struct StepOutput {
+    step: usize,
+    // Pending rustc diagnostic header waiting for its location line, one for each stream.
+    rustc_headers: [Mutex<Option<(String, String)>>; 2],
+}
+
+impl StepOutput {
+    pub fn new(step: usize) -> StepOutput {
+        StepOutput {
+            step,
+            rustc_headers: [Mutex::new(None), Mutex::new(None)],
+        }
+    }
+
+    pub fn line(&self, stream: OutputStream, data: &[u8]) {
+        if !is_message_format_json() {
+            print_output(stream, data);
+            return;
+        }
+
+        let line = String::from_utf8_lossy(data);
+        let line = line.trim_end_matches(['\r', '\n']);
+        emit(Event::Output {
+            step: Some(self.step),
+            stream,
+            line: line.to_string(),
+        });
+
+        if let Some(v) = DIAGNOSTIC_RE.captures(line) {
+            emit(Event::Diagnostic {
+                step: Some(self.step),
+                severity: v["severity"].to_string(),
+                file: v["file"].to_string(),
+                line: v["line"].parse().unwrap_or_default(),
+                column: v.name("column").and_then(|x| x.as_str().parse().ok()),
+                message: v["message"].to_string(),
+            });
+            return;
+        }
+
+        let mut rustc_header = self.rustc_headers[stream as usize].lock().unwrap();
+        if let Some(v) = RUSTC_HEADER_RE.captures(line) {
+            *rustc_header = Some((v["severity"].to_string(), v["message"].to_string()));
+        } else if let Some(v) = RUSTC_LOCATION_RE.captures(line) {
+            if let Some((severity, message)) = rustc_header.take() {
+                emit(Event::Diagnostic {
+                    step: Some(self.step),
+                    severity,
+                    file: v["file"].to_string(),
+                    line: v["line"].parse().unwrap_or_default(),
+                    column: v["column"].parse().ok(),
+                    message,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(line: &str) -> Option<(String, String, String, Option<String>, String)> {
+        DIAGNOSTIC_RE.captures(line).map(|x| {
+            (
+                x["severity"].to_string(),
+                x["file"].to_string(),
+                x["line"].to_string(),
+                x.name("column").map(|xx| xx.as_str().to_string()),
+                x["message"].to_string(),
+            )
+        })
+    }
+
+    #[test]
+    fn diagnostic_gcc() {
+        assert_eq!(
+            diagnostic("../src/main.c:3:5: error: expected ';'"),
+            Some((
+                "error".to_string(),
+                "../src/main.c".to_string(),
+                "3".to_string(),
+                Some("5".to_string()),
+                "expected ';'".to_string()
+            ))
+        );
+        assert_eq!(
+            diagnostic("main.c:10: fatal error: foo.h: No such file").map(|x| x.0),
+            Some("error".to_string())
+        );
+        assert_eq!(
+            diagnostic("main.c:1:2: warning: unused [-Wunused]").map(|x| x.4),
+            Some("unused [-Wunused]".to_string())
+        );
+    }
+
+    #[test]
+    fn diagnostic_valac() {
+        assert_eq!(
+            diagnostic("src/window.vala:12.9-12.20: error: The name `foo' does not exist"),
+            Some((
+                "error".to_string(),
+                "src/window.vala".to_string(),
+                "12".to_string(),
+                Some("9".to_string()),
+                "The name `foo' does not exist".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn diagnostic_not_matched() {
+        assert_eq!(diagnostic("[1/2] Compiling C object main.o"), None);
+        assert_eq!(diagnostic("error: linker failed"), None);
+    }
+
+    #[test]
+    fn diagnostic_rustc() {
+        let header = RUSTC_HEADER_RE
+            .captures("error[E0308]: mismatched types")
+            .unwrap();
+        assert_eq!(&header["severity"], "error");
+        assert_eq!(&header["message"], "mismatched types");
+
+        let location = RUSTC_LOCATION_RE.captures("  --> src/main.rs:4:9").unwrap();
+        assert_eq!(&location["file"], "src/main.rs");
+        assert_eq!(&location["line"], "4");
+        assert_eq!(&location["column"], "9");
+    }
+}
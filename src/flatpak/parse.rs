@@ -1,13 +1,18 @@
 use std::error::Error;
 use std::fs::read_to_string;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use crate::config::{resolve_path, Config};
 use crate::flatpak::types::ManifestSchema;
 use crate::manifest::Manifest;
 use crate::{box_error, full_println};
 
+/// Parse `manifest` if given, or the manifest in config, or *.Devel.json found in build-aux.
+///
+/// Relative `manifest` is relative to root directory.
 pub fn find_manifest_and_parse(
     root_directory: Option<PathBuf>,
+    manifest: Option<PathBuf>,
 ) -> Result<Manifest, Box<dyn Error>> {
     let work_directory = root_directory.unwrap_or(
         std::env::current_dir()
@@ -18,7 +23,58 @@ pub fn find_manifest_and_parse(
             .parse::<PathBuf>()?,
     );
 
-    let mut check_directory = work_directory.clone();
+    let config = Config::load(&work_directory)?;
+
+    let manifest_path = match manifest.as_ref().or(config.manifest.as_ref()) {
+        Some(v) => {
+            let path = resolve_path(&work_directory, v);
+            if !path.is_file() {
+                return box_error!("manifest {} not found", path.to_str().unwrap());
+            }
+            path
+        }
+        None => find_manifest(&work_directory)?,
+    };
+
+    let manifest_data = read_to_string(manifest_path.clone())?;
+
+    let schema: ManifestSchema = serde_json::from_str(manifest_data.as_str())?;
+
+    Ok(Manifest::new(work_directory, schema, manifest_path, config))
+}
+
+/// Options selecting and overriding the manifest, from command line or server request.
+#[derive(Clone, Default)]
+pub struct ParseOptions {
+    pub root_dir: Option<PathBuf>,
+    pub manifest: Option<PathBuf>,
+    pub module: Option<String>,
+    pub profile: Option<String>,
+    pub build_type: Option<String>,
+}
+
+impl ParseOptions {
+    /// Find and parse manifest, then apply options on top of config.
+    ///
+    /// Module and profile in options replace those in config, which are then not checked.
+    pub fn parse(&self) -> Result<Manifest, Box<dyn Error>> {
+        let mut manifest = find_manifest_and_parse(self.root_dir.clone(), self.manifest.clone())?;
+        if let Some(v) = self.module.clone().or(manifest.config.module.clone()) {
+            manifest.set_module(v.as_str())?;
+        }
+        if let Some(v) = self.profile.clone().or(manifest.config.profile.clone()) {
+            manifest.set_profile(v.as_str())?;
+        }
+        if let Some(v) = self.build_type.as_ref() {
+            manifest.build_type = v.to_owned();
+        }
+        Ok(manifest)
+    }
+}
+
+/// Find *.Devel.json in build-aux of `work_directory`.
+fn find_manifest(work_directory: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let mut check_directory = work_directory.to_path_buf();
 
     check_directory.push("build-aux");
 
@@ -57,9 +113,5 @@ pub fn find_manifest_and_parse(
         .unwrap()
         .path();
 
-    let manifest_data = read_to_string(manifest_path.clone())?;
-
-    let schema: ManifestSchema = serde_json::from_str(manifest_data.as_str())?;
-
-    Ok(Manifest::new(work_directory, schema, manifest_path))
+    Ok(manifest_path)
 }
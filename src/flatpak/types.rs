@@ -5,6 +5,7 @@ use racros::{AutoDebug, AutoStr};
 use serde::{Deserialize, Serialize};
 
 #[derive(AutoDebug, Default, Deserialize, Serialize)]
+#[serde(default)]
 pub struct BuildOption {
     #[serde(rename = "build-args")]
     pub build_args: Vec<String>,
@@ -21,8 +22,11 @@ pub struct BuildOption {
     #[serde(rename = "prepend-pkg-config-path")]
     pub prepend_pkg_config_path: Option<String>,
     pub env: HashMap<String, String>,
+    pub cflags: Option<String>,
+    pub cxxflags: Option<String>,
     #[serde(rename = "config-opts")]
     pub config_opts: Option<Vec<String>>,
+    pub prefix: Option<String>,
 }
 
 #[derive(AutoDebug, AutoStr, Deserialize, Serialize)]
@@ -37,7 +41,7 @@ pub enum BuildSystem {
     CmakeNinja,
     #[serde(rename = "simple")]
     Simple,
-    #[serde(rename = "auto-tools")]
+    #[serde(rename = "autotools", alias = "auto-tools")]
     Autotools,
     #[serde(rename = "qmake")]
     Qmake,
@@ -96,6 +100,15 @@ pub struct Module {
     pub build_options: Option<BuildOption>,
     #[serde(rename = "post-install")]
     pub post_install: Option<Vec<String>>,
+    pub builddir: Option<bool>,
+    pub subdir: Option<String>,
+    #[serde(rename = "make-args")]
+    pub make_args: Option<Vec<String>>,
+    #[serde(rename = "make-install-args")]
+    pub make_install_args: Option<Vec<String>>,
+    pub disabled: Option<bool>,
+    /// Nested modules, built before this module.
+    pub modules: Option<Vec<Module>>,
 }
 
 impl Display for Module {
@@ -104,6 +117,16 @@ impl Display for Module {
     }
 }
 
+#[derive(AutoDebug, Deserialize, Serialize)]
+pub struct AddExtension {
+    pub directory: String,
+    pub version: Option<String>,
+    pub versions: Option<String>,
+    pub subdirectories: Option<bool>,
+    #[serde(rename = "add-ld-path")]
+    pub add_ld_path: Option<String>,
+}
+
 #[derive(AutoDebug, Deserialize, Serialize)]
 pub struct ManifestSchema {
     pub id: Option<String>,
@@ -117,16 +140,29 @@ pub struct ManifestSchema {
     pub runtime_version: String,
     #[serde(rename = "sdk-extensions")]
     pub sdk_extensions: Option<Vec<String>>,
+    pub base: Option<String>,
+    #[serde(rename = "base-version")]
+    pub base_version: Option<String>,
+    #[serde(rename = "base-extensions")]
+    pub base_extensions: Option<Vec<String>>,
+    /// Empty for extensions, which run the command of parent app.
+    #[serde(default)]
     pub command: String,
-    #[serde(rename = "finish-args")]
+    #[serde(rename = "finish-args", default)]
     pub finish_args: Vec<String>,
+    #[serde(rename = "build-extension")]
+    pub build_extension: Option<bool>,
+    #[serde(rename = "extension-tag")]
+    pub extension_tag: Option<String>,
     #[serde(rename = "build-options")]
     pub build_options: Option<BuildOption>,
     #[serde(rename = "x-run-args")]
     pub x_run_args: Option<Vec<String>>,
+    #[serde(rename = "add-extensions")]
+    pub add_extensions: Option<HashMap<String, AddExtension>>,
 }
 
-#[derive(AutoStr, Deserialize, Serialize)]
+#[derive(AutoStr, Deserialize, PartialEq, Serialize)]
 #[autorule = "lowercase"]
 pub enum SdkExtension {
     #[serde(rename = "vala")]
@@ -134,3 +170,18 @@ pub enum SdkExtension {
     #[serde(rename = "rust")]
     Rust,
 }
+
+impl SdkExtension {
+    /// Known sdk extension from extension name, the part after sdk extension point.
+    ///
+    /// e.g. rust-stable and rust-nightly are `SdkExtension::Rust`.
+    pub fn from_name(name: &str) -> Option<SdkExtension> {
+        if name.starts_with("rust") {
+            Some(SdkExtension::Rust)
+        } else if name.starts_with("vala") {
+            Some(SdkExtension::Vala)
+        } else {
+            None
+        }
+    }
+}
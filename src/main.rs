@@ -1,14 +1,19 @@
 use std::env::set_var;
 use std::error::Error;
 use std::path::PathBuf;
+use std::process::exit;
 
 use crate::addon::set_override_env_prefix;
-use clap::ArgAction::Count;
+use clap::ArgAction::{Append, Count, SetTrue};
 use clap::{Arg, ArgMatches, Command};
 use lazy_static::lazy_static;
 
 use crate::constants::{APP_LOG_VAR, GIT_COMMIT_REVISION, GIT_COMMIT_TIME, GIT_TAG_VERSION};
-use crate::flatpak::parse::find_manifest_and_parse;
+use crate::event::{emit, is_message_format_json, set_message_format_json, Event};
+use crate::flatpak::parse::ParseOptions;
+use crate::process::install_signal_handlers;
+use crate::server::serve;
+use crate::watch::watch;
 
 lazy_static! {
     static ref VERSION: String =
@@ -16,26 +21,124 @@ lazy_static! {
 }
 
 mod addon;
+mod config;
 mod constants;
+mod event;
 mod flatpak;
 mod manifest;
+mod process;
+mod remap;
+mod server;
 mod util;
+mod watch;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let build_command = Command::new("build").about("build package");
-    let run_command = Command::new("run").about("run package");
+    let run_command = Command::new("run")
+        .about("run package")
+        .arg(
+            Arg::new("command")
+                .long("command")
+                .help("run another binary installed in /app/bin instead of manifest command"),
+        )
+        .arg(
+            Arg::new("sdk-extension")
+                .long("sdk-extension")
+                .action(Append)
+                .help("mount sdk extension when running, besides those in manifest"),
+        )
+        .arg(
+            Arg::new("app-args")
+                .index(2)
+                .num_args(0..)
+                .last(true)
+                .help("arguments passed to the app, after --"),
+        );
+
+    let shell_command = Command::new("shell")
+        .about("start an interactive shell in the build environment")
+        .arg(
+            Arg::new("runtime")
+                .long("runtime")
+                .action(SetTrue)
+                .help("use the runtime environment instead of the build environment"),
+        );
+    let exec_command = Command::new("exec")
+        .about("run a command in the build environment")
+        .arg(
+            Arg::new("runtime")
+                .long("runtime")
+                .action(SetTrue)
+                .help("use the runtime environment instead of the build environment"),
+        )
+        .arg(
+            Arg::new("exec-command")
+                .index(2)
+                .num_args(1..)
+                .last(true)
+                .required(true)
+                .help("command to run, after --"),
+        );
+
+    let stop_command = Command::new("stop").about("terminate the package started by run");
+    let clean_command = Command::new("clean").about("remove build outputs");
+    let watch_command =
+        Command::new("watch").about("rebuild and relaunch package when files change");
+    let serve_command =
+        Command::new("serve").about("serve manifest operations in JSON-RPC 2.0 over stdio");
+    let lsp_wrappers_command = Command::new("lsp-wrappers")
+        .about("generate wrappers running language servers in the build environment");
 
     let mut command = Command::new("fbh")
         .about("flatpak-build-helper")
         .version(VERSION.as_str())
         .subcommand(build_command)
         .subcommand(run_command)
+        .subcommand(stop_command)
+        .subcommand(shell_command)
+        .subcommand(exec_command)
+        .subcommand(lsp_wrappers_command)
+        .subcommand(clean_command)
+        .subcommand(watch_command)
+        .subcommand(serve_command)
         .arg(
             Arg::new("override-env-prefix")
                 .long("override-env-prefix")
                 .global(true)
                 .help("load envs those names have given prefix from host and override in building"),
         )
+        .arg(
+            Arg::new("buildtype")
+                .long("buildtype")
+                .global(true)
+                .help("meson build type, e.g. debug, debugoptimized, release, also mapped to cmake build type"),
+        )
+        .arg(
+            Arg::new("manifest")
+                .long("manifest")
+                .global(true)
+                .help("manifest path relative to root dir, instead of *.Devel.json in build-aux"),
+        )
+        .arg(
+            Arg::new("module")
+                .long("module")
+                .global(true)
+                .help("app module built in tree, dependencies are built until it"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .global(true)
+                .help("build profile in config, builds in its own _build-<profile> dir"),
+        )
+        .arg(
+            Arg::new("message-format")
+                .long("message-format")
+                .global(true)
+                .value_parser(["human", "json"])
+                .default_value("human")
+                .help("output format, json prints line-delimited events on stdout"),
+        )
         .arg(Arg::new("root-dir").index(1).global(true))
         .arg(
             Arg::new("verbose")
@@ -60,52 +163,135 @@ fn main() -> Result<(), Box<dyn Error>> {
         _ => {}
     }
 
-    match command_matches.subcommand() {
+    set_message_format_json(
+        command_matches
+            .get_one::<String>("message-format")
+            .is_some_and(|x| x == "json"),
+    );
+
+    let result = match command_matches.subcommand() {
         Some(("build", args)) => handle_build_command(args),
         Some(("run", args)) => handle_run_command(args),
+        Some(("stop", args)) => handle_stop_command(args),
+        Some(("shell", args)) => handle_shell_command(args),
+        Some(("exec", args)) => handle_exec_command(args),
+        Some(("lsp-wrappers", args)) => handle_lsp_wrappers_command(args),
+        Some(("clean", args)) => handle_clean_command(args),
+        Some(("watch", args)) => handle_watch_command(args),
+        Some(("serve", _)) => handle_serve_command(),
         _ => {
             command.print_help()?;
-            Ok(())
+            return Ok(());
         }
+    };
+
+    // serve turns on json message format by itself, and replies results in JSON-RPC instead.
+    if is_message_format_json() && command_matches.subcommand_name() != Some("serve") {
+        emit(Event::Result {
+            success: result.is_ok(),
+            message: result.as_ref().err().map(|x| x.to_string()),
+        });
+    }
+
+    result
+}
+
+fn parse_options(args: &ArgMatches) -> ParseOptions {
+    ParseOptions {
+        root_dir: args.get_one::<String>("root-dir").map(PathBuf::from),
+        manifest: args.get_one::<String>("manifest").map(PathBuf::from),
+        module: args.get_one::<String>("module").cloned(),
+        profile: args.get_one::<String>("profile").cloned(),
+        build_type: args.get_one::<String>("buildtype").cloned(),
     }
 }
 
 fn handle_build_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
-    let root_dir = args.get_one::<String>("root-dir").map(PathBuf::from);
-    let schema = find_manifest_and_parse(root_dir)?;
+    install_signal_handlers();
+    let schema = parse_options(args).parse()?;
 
     full_println!("build command, schema: {:#?}", schema);
 
-    debug_println!("check initialization");
-    if !schema.is_initialized() {
-        debug_println!("running build-init");
-        schema.init_build()?;
+    // TODO: Rebuild is flag came from cmdline.
+    schema.build_project(false)
+}
+
+fn handle_clean_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let schema = parse_options(args).parse()?;
+
+    schema.clean()
+}
+
+fn handle_watch_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    install_signal_handlers();
+    watch(parse_options(args))
+}
+
+fn handle_serve_command() -> Result<(), Box<dyn Error>> {
+    install_signal_handlers();
+    set_message_format_json(true);
+    serve()
+}
+
+fn handle_run_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    install_signal_handlers();
+    let mut schema = parse_options(args).parse()?;
+
+    schema.extra_sdk_extensions = args
+        .get_many::<String>("sdk-extension")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+
+    let app_args: Vec<String> = args
+        .get_many::<String>("app-args")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+
+    schema.build_project(false)?;
+    schema.run(
+        args.get_one::<String>("command").map(|x| x.as_str()),
+        &app_args,
+    )
+}
+
+fn handle_stop_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let schema = parse_options(args).parse()?;
+    if schema.stop() {
+        info_println!("stopped");
     } else {
-        debug_println!("skip build-init: already initialized");
+        info_println!("not running");
     }
+    Ok(())
+}
 
-    if PathBuf::from(&schema.repo_dir).exists() {
-        debug_println!("skip build: already built");
-        return Ok(());
-    }
+fn handle_shell_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let mut schema = parse_options(args).parse()?;
 
-    debug_println!("updating dependencies");
-    schema.update_dependencies()?;
+    let code = schema.shell(args.get_flag("runtime"))?;
+    exit(code)
+}
 
-    debug_println!("building dependencies");
-    schema.build_dependencies()?;
+fn handle_exec_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let mut schema = parse_options(args).parse()?;
 
-    debug_println!("building targets");
-    // TODO: Rebuild is flag came from cmdline.
-    schema.build(false)?;
+    let exec_command: Vec<String> = args
+        .get_many::<String>("exec-command")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
 
-    Ok(())
+    let code = schema.exec(args.get_flag("runtime"), &exec_command)?;
+    exit(code)
 }
 
-fn handle_run_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
-    let root_dir = args.get_one::<String>("root-dir").map(PathBuf::from);
-    let mut schema = find_manifest_and_parse(root_dir)?;
+fn handle_lsp_wrappers_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let schema = parse_options(args).parse()?;
 
-    schema.build(false)?;
-    schema.run()
+    for wrapper in schema.generate_lsp_wrappers()? {
+        info_println!("{}", wrapper.to_str().unwrap());
+    }
+
+    Ok(())
 }
@@ -1,25 +1,45 @@
 use std::collections::HashMap;
 use std::error::Error;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::path::PathBuf;
-use std::process::Command;
+use std::fs::{
+    create_dir_all, read_to_string, remove_dir_all, set_permissions, OpenOptions, Permissions,
+};
+use std::io::{IsTerminal, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::str::from_utf8;
+use std::sync::OnceLock;
+use std::time::Instant;
 
 use libc::getuid;
 use racros::AutoDebug;
 use regex::Regex;
 
 use crate::addon::load_envs_from_os;
+use crate::config::{resolve_path, Config, Profile};
 use crate::constants::{
-    BUILD_SYSTEM_BUILD_DIR, FONT_DIR_CONTENT_HEADER, SYSTEM_FONTS_DIR, SYSTEM_FONT_CACHE_DIRS,
-    SYSTEM_LOCAL_FONT_DIR,
+    BUILD_SYSTEM_BUILD_DIR, COMPILE_COMMANDS_FILE, DEPENDENCIES_BUILT_FILE,
+    FONT_DIR_CONTENT_HEADER, INIT_RECORD_FILE, MESON_DEFAULT_BUILD_TYPE, RUN_PID_FILE,
+    SANDBOX_SHELL, SDK_EXTENSIONS_DIR, SDK_EXTENSION_POINT, STATE_FILE, SYSTEM_FONTS_DIR,
+    SYSTEM_FONT_CACHE_DIRS, SYSTEM_LOCAL_FONT_DIR,
+};
+use crate::event::{emit, is_message_format_json, print_output, Event, OutputStream, StepOutput};
+use crate::flatpak::types::{
+    BuildOption, BuildSystem, ManifestSchema, Module, SdkExtension, SourceType,
 };
-use crate::flatpak::types::{BuildOption, BuildSystem, ManifestSchema, Module};
+use crate::process::{
+    clear_interrupted_step, is_cancelled, process_start_time, read_interrupted_step,
+    set_current_step, set_state_file, spawn_child, terminate_process_group, wait_child_with_lines,
+    wait_child_with_output,
+};
+use crate::remap::PathRemapper;
 use crate::util::{
-    get_host_envs, get_user_cache_dir, get_user_fonts_cache_dir, get_user_fonts_dir,
+    fnv1a_hash, get_flatpak_arch, get_host_envs, get_user_cache_dir, get_user_fonts_cache_dir,
+    get_user_fonts_dir, parse_key_file, shell_quote, KeyFile,
 };
-use crate::{box_error, debug_println, full_println};
+use crate::{box_error, debug_println, full_println, info_println};
 
 static ENV_NAME_LIT: [&str; 11] = [
     "COLORTERM",
@@ -35,13 +55,24 @@ static ENV_NAME_LIT: [&str; 11] = [
     "AT_SPI_BUS_ADDRESS",
 ];
 
+/// Compiler options followed by an include dir, joined or as the next argument.
+static INCLUDE_OPTIONS: [&str; 4] = ["-I", "-isystem", "-iquote", "-idirafter"];
+
+/// Tools to generate language server wrappers for, with the sdk extension providing them.
+static LSP_WRAPPER_TOOLS: [(&str, Option<SdkExtension>); 4] = [
+    ("rust-analyzer", Some(SdkExtension::Rust)),
+    ("cargo", Some(SdkExtension::Rust)),
+    ("clangd", None),
+    ("vala-language-server", Some(SdkExtension::Vala)),
+];
+
 /// Combine environment variables from manifest schema, host env, and default values.
 ///
 /// e.g. For `PATH` env, add the following env string to `ret` arg:
 /// "--env=PATH=${prepend env from schema}:${host env}:${default env value}:${append env from schema}"
 macro_rules! override_env {
-    ($ret: tt, $manifest: tt, $env_name: tt, $default_value: ident, $prepend_ident: ident, $append_ident: ident) => {
-        let module = $manifest.module().unwrap();
+    ($ret: tt, $manifest: tt, $module: tt, $env_name: tt, $default_value: ident, $prepend_ident: ident, $append_ident: ident) => {
+        let module = $module;
         let prepend_env_list = vec![
             $manifest
                 .manifest
@@ -99,21 +130,59 @@ pub struct Manifest {
     pub build_dir: PathBuf,
     pub state_dir: PathBuf,
     pub id: String,
+    pub build_type: String,
+    /// Sdk extensions mounted when running besides those in manifest.
+    pub extra_sdk_extensions: Vec<String>,
+    pub config: Config,
+    /// Selected build profile in config.
+    pub profile: Option<String>,
+    /// Selected app module, detected if None.
+    pub module_name: Option<String>,
 
     fonts_args: Vec<String>,
     a11y_bus_args: Vec<String>,
+    /// Cache of `sdk_metadata`.
+    sdk_metadata: OnceLock<KeyFile>,
+    /// Cache of `parent_app_metadata`, for extensions.
+    parent_app_metadata: OnceLock<KeyFile>,
 }
 
 impl Manifest {
-    pub fn new(root_dir: PathBuf, manifest: ManifestSchema, manifest_path: PathBuf) -> Manifest {
-        let build_dir = root_dir.clone().join(".flatpak");
-        let repo_dir = build_dir.clone().join("repo");
-
-        let state_dir = build_dir.join("flatpak-builder");
-
+    pub fn new(
+        root_dir: PathBuf,
+        manifest: ManifestSchema,
+        manifest_path: PathBuf,
+        config: Config,
+    ) -> Manifest {
         // FIXME: manifest.id may be None.
         let id = manifest.id.clone().unwrap();
 
+        // Every manifest has its own build tree, keyed by id and manifest path relative to root
+        // dir, so that builds of several manifests in one project do not touch each other.
+        let relative_manifest_path = manifest_path
+            .strip_prefix(&root_dir)
+            .unwrap_or(&manifest_path);
+        let build_tree = format!(
+            "{}-{:08x}",
+            id,
+            fnv1a_hash(relative_manifest_path.as_os_str().as_bytes()) as u32
+        );
+        // Configured dirs also have a sub dir for each manifest, except state dir, which is shared
+        // to reuse downloads and ccache across manifests and projects.
+        let resolve = |path: &Option<PathBuf>, default: PathBuf| match path {
+            Some(v) => resolve_path(&root_dir, v).join(&build_tree),
+            None => default,
+        };
+        let build_dir = resolve(
+            &config.build_dir,
+            root_dir.join(".flatpak").join(&build_tree),
+        );
+        let repo_dir = resolve(&config.repo_dir, build_dir.join("repo"));
+        let state_dir = match config.state_dir.as_ref() {
+            Some(v) => resolve_path(&root_dir, v),
+            None => build_dir.join("flatpak-builder"),
+        };
+
         Manifest {
             root_dir,
             manifest,
@@ -122,37 +191,200 @@ impl Manifest {
             build_dir,
             state_dir,
             id,
+            build_type: config
+                .buildtype
+                .clone()
+                .unwrap_or(MESON_DEFAULT_BUILD_TYPE.to_string()),
+            extra_sdk_extensions: vec![],
+            config,
+            profile: None,
+            module_name: None,
             fonts_args: vec![],
             a11y_bus_args: vec![],
+            sdk_metadata: OnceLock::new(),
+            parent_app_metadata: OnceLock::new(),
         }
     }
 
     pub fn init_build(&self) -> Result<(), Box<dyn Error>> {
+        self.check_sdk_extensions()?;
+        self.check_base()?;
+        if self.is_extension() {
+            self.parent_app_metadata()?;
+        }
+
         // flatpak build-init $RepoDir $Id $Sdk $Runtime $runtimeVersion
         let mut cmd = Command::new("flatpak");
 
-        cmd.arg("build-init")
-            .arg(self.repo_dir.to_str().unwrap())
+        cmd.arg("build-init");
+        self.sdk_extensions()
+            .iter()
+            .for_each(|x| _ = cmd.arg(format!("--sdk-extension={}", x)));
+        cmd.args(self.get_base_args());
+        cmd.args(self.get_extension_init_args());
+        cmd.arg(self.repo_dir.to_str().unwrap())
             .arg(&self.id)
             .arg(&self.manifest.sdk)
             .arg(&self.manifest.runtime)
             .arg(&self.manifest.runtime_version);
 
         full_println!("initialize command: {:#?}", cmd);
+        set_current_step("build-init");
 
-        let cmd_output = cmd.output()?;
-        print!("{}", from_utf8(cmd_output.stdout.as_ref()).unwrap());
+        let cmd_output = wait_child_with_output(spawn_child(
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped()),
+        )?)?;
+        print_output(OutputStream::Stdout, &cmd_output.stdout);
 
         if !cmd_output.status.success() {
-            eprintln!(
+            return box_error!(
                 "failed to build-init {}",
                 from_utf8(cmd_output.stderr.as_ref()).unwrap()
             );
         }
 
+        // Sdk extensions are not recorded in metadata, keep them to detect stale build-init.
+        let record = serde_json::json!({
+            "sdk-extensions": self.sdk_extensions(),
+            "base": self.get_base_args(),
+        });
+        if let Err(e) = std::fs::write(self.init_record_file(), record.to_string()) {
+            eprintln!("failed to record build-init: {}", e);
+        }
+
+        Ok(())
+    }
+
+    /// Record of options build-init ran with that are not in repo metadata.
+    fn init_record_file(&self) -> PathBuf {
+        self.build_dir.join(INIT_RECORD_FILE)
+    }
+
+    /// Reason why the initialized repo dir does not match the manifest any more, e.g. after
+    /// runtime-version changed, None if it still matches.
+    fn stale_init_reason(&self) -> Option<String> {
+        let metadata = read_to_string(self.repo_dir.join("metadata")).ok()?;
+        let groups = parse_key_file(metadata.as_str());
+        let application = groups.get("Application").or(groups.get("Runtime"))?;
+        let arch = get_flatpak_arch();
+        let (sdk_id, sdk_branch) = self.sdk_id_and_branch();
+
+        let mut expected = vec![("id", application.get("name"), self.id.to_owned())];
+        if self.is_extension() {
+            // Runtime of an extension is the parent app, recorded as the extended ref instead.
+            expected.push((
+                "parent app",
+                groups.get("ExtensionOf").and_then(|x| x.get("ref")),
+                self.parent_app_ref(),
+            ));
+        } else {
+            expected.push((
+                "runtime",
+                application.get("runtime"),
+                format!(
+                    "{}/{}/{}",
+                    self.manifest.runtime, arch, self.manifest.runtime_version
+                ),
+            ));
+        }
+        expected.push((
+            "sdk",
+            application.get("sdk"),
+            format!("{}/{}/{}", sdk_id, arch, sdk_branch),
+        ));
+        for (name, actual, value) in expected {
+            let actual = actual.map(|x| x.as_str()).unwrap_or_default();
+            if actual != value {
+                return Some(format!("{} changed from {} to {}", name, actual, value));
+            }
+        }
+
+        // Build dirs initialized before extensions were recorded are not checked.
+        let record: serde_json::Value =
+            serde_json::from_str(read_to_string(self.init_record_file()).ok()?.as_str()).ok()?;
+        let mut actual: Vec<String> = record["sdk-extensions"]
+            .as_array()?
+            .iter()
+            .filter_map(|x| x.as_str().map(|xx| xx.to_string()))
+            .collect();
+        let mut expected = self.sdk_extensions();
+        actual.sort();
+        expected.sort();
+        if actual != expected {
+            return Some(format!(
+                "sdk extensions changed from {:?} to {:?}",
+                actual, expected
+            ));
+        }
+
+        let actual: Vec<String> = record["base"]
+            .as_array()
+            .map(|x| {
+                x.iter()
+                    .filter_map(|xx| xx.as_str().map(|xxx| xxx.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let expected = self.get_base_args();
+        if actual != expected {
+            return Some(format!("base changed from {:?} to {:?}", actual, expected));
+        }
+
+        None
+    }
+
+    /// Remove the stale repo dir and everything built against it, including kept build dirs of
+    /// dependencies of this manifest, so that nothing built with the old runtime or sdk is
+    /// reused.
+    fn remove_stale_init(&self) -> Result<(), Box<dyn Error>> {
+        let mut dirs = vec![self.repo_dir.clone()];
+        dirs.extend(self.all_build_system_dirs());
+        for dir in dirs {
+            if dir.exists() {
+                debug_println!("removing {}", dir.to_str().unwrap());
+                remove_dir_all(&dir)?;
+            }
+        }
+        for path in self.kept_build_dirs() {
+            debug_println!("removing {}", path.to_str().unwrap());
+            if path.is_symlink() {
+                std::fs::remove_file(&path)?;
+            } else {
+                remove_dir_all(&path)?;
+            }
+        }
+        for file in [self.init_record_file(), self.dependencies_built_file()] {
+            if file.exists() {
+                std::fs::remove_file(file)?;
+            }
+        }
         Ok(())
     }
 
+    /// Kept build dirs of modules in manifest in flatpak-builder state dir, `<module>-<n>` and
+    /// the `<module>` link to the last one.
+    fn kept_build_dirs(&self) -> Vec<PathBuf> {
+        let names: Vec<&str> = self.modules().iter().map(|x| x.name.as_str()).collect();
+        let is_kept_build_dir = |name: &str| {
+            names.iter().any(|x| {
+                name == *x
+                    || name
+                        .strip_prefix(x)
+                        .and_then(|xx| xx.strip_prefix('-'))
+                        .is_some_and(|xx| xx.parse::<u32>().is_ok())
+            })
+        };
+        self.state_dir
+            .join("build")
+            .read_dir()
+            .into_iter()
+            .flatten()
+            .filter_map(|x| x.ok())
+            .filter(|x| is_kept_build_dir(x.file_name().to_str().unwrap_or_default()))
+            .map(|x| x.path())
+            .collect()
+    }
+
     pub fn is_initialized(&self) -> bool {
         if !self.root_dir.exists() {
             full_println!(
@@ -185,19 +417,24 @@ impl Manifest {
             .arg(format!("--state-dir={}", self.state_dir.to_str().unwrap()).as_str())
             .arg(format!(
                 "--stop-at={}",
-                self.module().expect("no module found in manifest")
+                self.in_tree_modules()
+                    .first()
+                    .expect("no module found in manifest")
             ))
             .arg(self.repo_dir.to_str().unwrap())
             .arg(self.path());
 
         full_println!("update dependencies command: {:#?}", cmd);
+        set_current_step("update dependencies");
 
-        let cmd_output = cmd.output()?;
+        let cmd_output = wait_child_with_output(spawn_child(
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped()),
+        )?)?;
 
-        print!("{}", from_utf8(cmd_output.stdout.as_ref()).unwrap());
+        print_output(OutputStream::Stdout, &cmd_output.stdout);
 
         if !cmd_output.status.success() {
-            eprintln!(
+            return box_error!(
                 "failed to update dependencies {}",
                 from_utf8(cmd_output.stderr.as_ref()).unwrap()
             );
@@ -206,14 +443,237 @@ impl Manifest {
         Ok(())
     }
 
-    fn module(&self) -> Option<&Module> {
-        self.manifest.modules.last()
+    /// App module built in tree.
+    ///
+    /// The module selected by --module or config if any, otherwise the last module with a dir
+    /// source pointing at root dir, otherwise the last module.
+    pub fn module(&self) -> Option<&Module> {
+        let modules = self.modules();
+        if let Some(name) = self.module_name.as_ref() {
+            return modules.into_iter().find(|x| &x.name == name);
+        }
+        modules
+            .iter()
+            .rev()
+            .find(|x| self.is_root_dir_module(x))
+            .or(modules.last())
+            .copied()
+    }
+
+    /// Enabled modules in build order, nested modules before their parent.
+    fn modules(&self) -> Vec<&Module> {
+        fn collect<'a>(modules: &'a [Module], ret: &mut Vec<&'a Module>) {
+            for module in modules.iter().filter(|x| !x.disabled.unwrap_or(false)) {
+                collect(module.modules.as_deref().unwrap_or_default(), ret);
+                ret.push(module);
+            }
+        }
+
+        let mut ret = vec![];
+        collect(&self.manifest.modules, &mut ret);
+        ret
+    }
+
+    /// Whether `module` has a dir source pointing at root dir.
+    fn is_root_dir_module(&self, module: &Module) -> bool {
+        let root_dir = match self.root_dir.canonicalize() {
+            Ok(v) => v,
+            Err(_) => return false,
+        };
+        module
+            .sources
+            .iter()
+            .filter(|x| matches!(x.source_type, SourceType::Dir))
+            .filter_map(|x| x.path.as_ref())
+            .any(|x| self.dir_source_path(x).is_some_and(|xx| xx == root_dir))
+    }
+
+    /// Select app module `name` instead of detecting it.
+    pub fn set_module(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        let names: Vec<&str> = self.modules().iter().map(|x| x.name.as_str()).collect();
+        if !names.contains(&name) {
+            return box_error!(
+                "module {} not found in manifest, available: {:?}",
+                name,
+                names
+            );
+        }
+        self.module_name = Some(name.to_string());
+        Ok(())
     }
 
     fn path(&self) -> &str {
         self.manifest_path.to_str().unwrap()
     }
 
+    /// Local modules built in tree, in build order, the app module is the last one.
+    ///
+    /// Besides the app module, these are the modules right before it whose sources are all dir
+    /// sources under root dir. flatpak-builder stops at the first of them, so a module with other
+    /// sources ends the run and everything before it is built as a dependency.
+    fn in_tree_modules(&self) -> Vec<&Module> {
+        let app_module = match self.module() {
+            Some(v) => v,
+            None => return vec![],
+        };
+        let modules = self.modules();
+        let end = modules
+            .iter()
+            .position(|x| std::ptr::eq(*x, app_module))
+            .unwrap();
+        let mut start = end;
+        while start > 0 && self.is_local_module(modules[start - 1]) {
+            start -= 1;
+        }
+        modules[start..=end].to_vec()
+    }
+
+    /// Whether all sources of `module` are dir sources under root dir.
+    fn is_local_module(&self, module: &Module) -> bool {
+        let root_dir = match self.root_dir.canonicalize() {
+            Ok(v) => v,
+            Err(_) => return false,
+        };
+        !module.sources.is_empty()
+            && module.sources.iter().all(|x| {
+                matches!(x.source_type, SourceType::Dir)
+                    && self
+                        .dir_source_path(x.path.as_deref().unwrap_or_default())
+                        .is_some_and(|xx| xx.starts_with(&root_dir))
+            })
+    }
+
+    /// Dir source `path` on host, relative paths are relative to the manifest dir.
+    fn dir_source_path(&self, path: &str) -> Option<PathBuf> {
+        let manifest_dir = self.manifest_path.parent().unwrap_or(&self.root_dir);
+        manifest_dir.join(path).canonicalize().ok()
+    }
+
+    fn is_app_module(&self, module: &Module) -> bool {
+        self.module().is_some_and(|x| std::ptr::eq(x, module))
+    }
+
+    /// Directory holding sources of `module`, joined with the module `subdir` if any.
+    ///
+    /// `root_dir` for the app module, the dir source path for other in tree modules.
+    fn source_dir(&self, module: &Module) -> PathBuf {
+        let dir = if self.is_app_module(module) {
+            self.root_dir.clone()
+        } else {
+            module
+                .sources
+                .iter()
+                .filter(|x| matches!(x.source_type, SourceType::Dir))
+                .find_map(|x| self.dir_source_path(x.path.as_deref().unwrap_or_default()))
+                .unwrap_or(self.root_dir.clone())
+        };
+        match module.subdir.as_ref() {
+            Some(subdir) => dir.join(subdir),
+            None => dir,
+        }
+    }
+
+    /// Build dir of the app module build system, where meson, cmake or qmake builds.
+    ///
+    /// Suffixed with profile name when a profile is selected, e.g. _build-asan.
+    pub fn build_system_dir(&self) -> PathBuf {
+        let dir = self.default_build_system_dir();
+        match self.profile.as_ref() {
+            Some(v) => {
+                let mut name = dir.file_name().unwrap_or_default().to_os_string();
+                name.push(format!("-{}", v));
+                dir.with_file_name(name)
+            }
+            None => dir,
+        }
+    }
+
+    /// Build dir of `module` build system, other in tree modules than the app module build in
+    /// a dir named after the module in the app module build dir suffixed with .modules, e.g.
+    /// _build.modules/libfoo, apart from build dirs of profiles.
+    fn module_build_dir(&self, module: &Module) -> PathBuf {
+        let dir = self.build_system_dir();
+        if self.is_app_module(module) {
+            return dir;
+        }
+        let mut name = dir.file_name().unwrap_or_default().to_os_string();
+        name.push(".modules");
+        dir.with_file_name(name).join(&module.name)
+    }
+
+    /// Build system dir of the app module without profile.
+    fn default_build_system_dir(&self) -> PathBuf {
+        match self.config.build_system_dir.as_ref() {
+            Some(v) => resolve_path(&self.root_dir, v).join(self.build_dir.file_name().unwrap()),
+            None => self.build_dir.join(BUILD_SYSTEM_BUILD_DIR),
+        }
+    }
+
+    /// Existing build system dirs of all profiles and in tree modules, e.g. _build, _build-asan
+    /// and _build.modules.
+    fn all_build_system_dirs(&self) -> Vec<PathBuf> {
+        let dir = self.default_build_system_dir();
+        let name = dir
+            .file_name()
+            .unwrap_or_default()
+            .to_str()
+            .unwrap_or_default();
+        let entries = match dir.parent().and_then(|x| x.read_dir().ok()) {
+            Some(v) => v,
+            None => return vec![],
+        };
+        entries
+            .filter_map(|x| x.ok())
+            .filter(|x| {
+                let file_name = x.file_name();
+                let file_name = file_name.to_str().unwrap_or_default();
+                file_name == name
+                    || file_name
+                        .strip_prefix(name)
+                        .is_some_and(|xx| xx.starts_with('-') || xx.starts_with('.'))
+            })
+            .map(|x| x.path())
+            .collect()
+    }
+
+    /// Build dirs of all in tree modules.
+    pub fn build_system_dirs(&self) -> Vec<PathBuf> {
+        self.in_tree_modules()
+            .into_iter()
+            .map(|x| self.module_build_dir(x))
+            .collect()
+    }
+
+    /// Select build profile `name` in config, build type is the one in profile, or in config.
+    pub fn set_profile(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        let profile = match self.config.profiles.get(name) {
+            Some(v) => v,
+            None => {
+                let mut names: Vec<&String> = self.config.profiles.keys().collect();
+                names.sort();
+                return box_error!(
+                    "profile {} not found in config, available: {:?}",
+                    name,
+                    names
+                );
+            }
+        };
+        // Start from config buildtype, so that one of a previously selected profile is not kept.
+        self.build_type = profile
+            .buildtype
+            .clone()
+            .or(self.config.buildtype.clone())
+            .unwrap_or(MESON_DEFAULT_BUILD_TYPE.to_string());
+        self.profile = Some(name.to_string());
+        Ok(())
+    }
+
+    fn build_profile(&self) -> Option<&Profile> {
+        self.profile
+            .as_ref()
+            .and_then(|x| self.config.profiles.get(x))
+    }
+
     pub fn build_dependencies(&self) -> Result<(), Box<dyn Error>> {
         let mut cmd = Command::new("flatpak-builder");
         cmd.arg("--ccache")
@@ -225,19 +685,24 @@ impl Manifest {
             .arg(format!("--state-dir={}", self.state_dir.to_str().unwrap()).as_str())
             .arg(format!(
                 "--stop-at={}",
-                self.module().expect("no module found in manifest")
+                self.in_tree_modules()
+                    .first()
+                    .expect("no module found in manifest")
             ))
             .arg(self.repo_dir.to_str().unwrap())
             .arg(self.path());
 
-        let cmd_output = cmd.output()?;
+        set_current_step("build dependencies");
+        let cmd_output = wait_child_with_output(spawn_child(
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped()),
+        )?)?;
 
         full_println!("build dependencies command: {:#?}", cmd);
 
-        print!("{}", from_utf8(cmd_output.stdout.as_ref()).unwrap());
+        print_output(OutputStream::Stdout, &cmd_output.stdout);
 
         if !cmd_output.status.success() {
-            eprintln!(
+            return box_error!(
                 "failed to build dependencies {}",
                 from_utf8(cmd_output.stderr.as_ref()).unwrap()
             );
@@ -246,22 +711,164 @@ impl Manifest {
         Ok(())
     }
 
-    pub fn build(&self, rebuild: bool) -> Result<(), Box<dyn Error>> {
+    /// Initialize, prepare dependencies and build, each step is skipped if already done.
+    ///
+    /// Every build goes through here, so that stale build dirs are always initialized again.
+    pub fn build_project(&self, rebuild: bool) -> Result<(), Box<dyn Error>> {
+        set_state_file(self.state_file());
+        if let Some(step) = read_interrupted_step(&self.state_file()) {
+            info_println!("previous build was interrupted during {}", step);
+        }
+
+        debug_println!("check initialization");
+        if self.is_initialized() {
+            if let Some(reason) = self.stale_init_reason() {
+                info_println!("build dir is stale: {}, running build-init again", reason);
+                self.remove_stale_init()?;
+            }
+        }
+
+        // Configured dirs may be outside project root, build system dirs are mounted into the
+        // sandbox and must exist before.
+        create_dir_all(&self.build_dir)?;
+        for dir in self.build_system_dirs() {
+            create_dir_all(dir)?;
+        }
+
+        if !self.is_initialized() {
+            debug_println!("running build-init");
+            self.init_build()?;
+        } else {
+            debug_println!("skip build-init: already initialized");
+        }
+
+        if self.dependencies_built_file().exists() {
+            debug_println!("skip dependencies: already built");
+        } else {
+            debug_println!("updating dependencies");
+            self.update_dependencies()?;
+
+            debug_println!("building dependencies");
+            self.build_dependencies()?;
+            std::fs::write(self.dependencies_built_file(), "")?;
+        }
+
+        debug_println!("building targets");
+        self.build(rebuild)
+    }
+
+    /// Remove the repo dir and build system build dirs of all profiles, flatpak-builder state
+    /// dir is kept to reuse downloads and caches.
+    pub fn clean(&self) -> Result<(), Box<dyn Error>> {
+        let mut dirs = vec![self.repo_dir.clone()];
+        dirs.extend(self.all_build_system_dirs());
+        for dir in dirs {
+            if dir.exists() {
+                debug_println!("removing {}", dir.to_str().unwrap());
+                remove_dir_all(&dir)?;
+            }
+        }
+        if self.dependencies_built_file().exists() {
+            std::fs::remove_file(self.dependencies_built_file())?;
+        }
+        Ok(())
+    }
+
+    /// Marks dependencies built into the repo dir, removed along with the repo dir.
+    fn dependencies_built_file(&self) -> PathBuf {
+        self.build_dir.join(DEPENDENCIES_BUILT_FILE)
+    }
+
+    fn build(&self, rebuild: bool) -> Result<(), Box<dyn Error>> {
+        set_state_file(self.state_file());
         debug_println!("setup command...");
         let mut commands = self.setup_command(rebuild)?;
+        let remapper = self.path_remapper();
+        let total = commands.len();
         debug_println!("running build commands");
-        for command in &mut commands {
-            command.args(load_envs_from_os());
+        for (index, command) in commands.iter_mut().enumerate() {
+            command.args(self.get_override_envs());
             debug_println!("{:#?}", command);
-            if !command.status()?.success() {
+
+            if is_cancelled() {
+                return box_error!("build cancelled");
+            }
+
+            let step = index + 1;
+            set_current_step(
+                format!(
+                    "build step {}/{}: {}",
+                    step,
+                    total,
+                    command.get_program().to_string_lossy()
+                )
+                .as_str(),
+            );
+            let json = is_message_format_json();
+            if json {
+                let mut command_line = vec![command.get_program().to_string_lossy().to_string()];
+                command_line.extend(command.get_args().map(|x| x.to_string_lossy().to_string()));
+                emit(Event::StepStarted {
+                    step,
+                    total,
+                    command: command_line,
+                });
+            }
+
+            let start_time = Instant::now();
+            let step_output = StepOutput::new(step);
+            let status = remapper.run(command, |stream, line| step_output.line(stream, line))?;
+            if json {
+                emit(Event::StepFinished {
+                    step,
+                    duration_ms: start_time.elapsed().as_millis(),
+                    exit_code: status.code(),
+                });
+            }
+
+            if !status.success() {
                 return box_error!("failed to build");
             }
         }
         debug_println!("build success");
+        set_current_step("");
+        clear_interrupted_step(&self.state_file());
+
+        if let Err(e) = self.update_host_compile_commands() {
+            let message = format!("failed to update host compile_commands.json: {}", e);
+            if is_message_format_json() {
+                emit(Event::Warning { message });
+            } else {
+                eprintln!("{}", message);
+            }
+        }
+
         Ok(())
     }
 
-    fn setup_command(&self, rebuild: bool) -> Result<Vec<Command>, Box<dyn Error>> {
+    /// Project state file, records the step interrupted by signal.
+    fn state_file(&self) -> PathBuf {
+        self.build_dir.join(STATE_FILE)
+    }
+
+    fn path_remapper(&self) -> PathRemapper {
+        PathRemapper::new(
+            self.in_tree_modules()
+                .into_iter()
+                .map(|x| (x.name.as_str(), self.source_dir(x)))
+                .collect(),
+            &self.state_dir,
+            &self.repo_dir,
+        )
+    }
+
+    /// Args for `flatpak build` in build steps of the app module, before the repo dir.
+    fn get_build_args(&self) -> Vec<String> {
+        self.get_module_build_args(self.module().unwrap())
+    }
+
+    /// Args for `flatpak build` in build steps of `module`, before the repo dir.
+    fn get_module_build_args(&self, module: &Module) -> Vec<String> {
         let collect_envs = |x: &HashMap<String, String>| -> Vec<String> {
             x.iter()
                 .map(|(key, value)| format!("--env={}={}", key, value))
@@ -278,9 +885,7 @@ impl Manifest {
         );
 
         let module_envs = collect_envs(
-            &self
-                .module()
-                .unwrap()
+            &module
                 .build_options
                 .as_ref()
                 .unwrap_or(&BuildOption::default())
@@ -295,24 +900,57 @@ impl Manifest {
             "--share=network".to_string(),
             format!("--filesystem={}", self.root_dir.to_str().unwrap()),
             format!("--filesystem={}", self.repo_dir.to_str().unwrap()),
+            format!(
+                "--filesystem={}",
+                self.module_build_dir(module).to_str().unwrap()
+            ),
         ];
+        // Envs in build options override flags, like in flatpak-builder.
+        build_args.extend(self.get_flags_envs(module));
         build_args.extend(build_envs);
 
-        build_args.extend(self.get_envs());
+        build_args.extend(self.get_envs(module));
+        build_args.extend(self.get_builder_envs(module));
+        if self.is_app_module(module) {
+            build_args.extend(self.get_profile_envs());
+        }
+        build_args.extend(self.config.build_args.to_owned());
 
         // Need these?
         // build_args.push(host_var_path);
         // build_args.push("--env=LD_LIBRARY_PATH=/app/lib/".to_string());
         // build_args.push("--env=PKG_CONFIG_PATH=/app/lib/pkgconfig:/app/share/pkgconfig:/usr/lib/pkgconfig:/usr/share/pkgconfig".to_string());
 
+        build_args
+    }
+
+    /// Commands building every in tree module in build order.
+    fn setup_command(&self, rebuild: bool) -> Result<Vec<Command>, Box<dyn Error>> {
+        let modules = self.in_tree_modules();
+        if modules.is_empty() {
+            return box_error!("module not found in manifest");
+        }
+
+        let mut commands: Vec<Command> = vec![];
+        for module in modules {
+            commands.extend(self.setup_module_command(module, rebuild)?);
+        }
+
+        debug_println!("build commands count: {}", commands.len());
+        full_println!("build commands: {:#?}", commands);
+        Ok(commands)
+    }
+
+    fn setup_module_command(
+        &self,
+        module: &Module,
+        rebuild: bool,
+    ) -> Result<Vec<Command>, Box<dyn Error>> {
+        let mut build_args = self.get_module_build_args(module);
+        build_args.extend(self.get_color_envs());
+
         let mut config_opts: Vec<String> = vec![];
-        config_opts.extend(
-            self.module()
-                .unwrap()
-                .config_opts
-                .to_owned()
-                .unwrap_or_default(),
-        );
+        config_opts.extend(module.config_opts.to_owned().unwrap_or_default());
 
         config_opts.extend(
             self.manifest
@@ -324,49 +962,65 @@ impl Manifest {
                 .unwrap_or_default(),
         );
 
-        let build_system = self
-            .module()
-            .ok_or("module not found in manifest")?
+        // Profiles apply to the app module only, dependencies may not know its config-opts.
+        if let Some(v) = self.build_profile().filter(|_| self.is_app_module(module)) {
+            config_opts.extend(v.config_opts.to_owned());
+        }
+
+        // Same default as flatpak-builder.
+        let build_system = module
             .build_system
             .as_ref()
-            .ok_or("build-system not found in manifest module")?;
+            .unwrap_or(&BuildSystem::Autotools);
+
+        debug_println!(
+            "module {} build-system: {}",
+            module.name,
+            build_system.to_string()
+        );
 
-        debug_println!("build-system: {}", build_system.to_string());
+        let module_build_commands = module.build_commands.to_owned().unwrap_or_default();
+        let module_post_install = module.post_install.to_owned().unwrap_or_default();
 
-        let commands = match *build_system {
-            BuildSystem::Autotools => self.get_autotools_commands(rebuild, build_args, config_opts),
+        let mut commands = match *build_system {
+            BuildSystem::Autotools => {
+                self.get_autotools_commands(module, rebuild, build_args.clone(), config_opts)
+            }
             BuildSystem::Cmake | BuildSystem::CmakeNinja => {
-                self.get_cmake_commands(rebuild, build_args, config_opts)
-            }
-            BuildSystem::Meson => self.get_meson_commands(rebuild, build_args, config_opts),
-            BuildSystem::Simple => self.get_simple_commands(
-                self.module()
-                    .ok_or(
-                        "failed to get build command: build-system is Simple but no modules found",
-                    )?
-                    .build_commands
-                    .to_owned()
-                    .unwrap_or_default(),
-                build_args,
-            ),
+                self.get_cmake_commands(module, rebuild, build_args.clone(), config_opts)
+            }
+            BuildSystem::Meson => {
+                self.get_meson_commands(module, rebuild, build_args.clone(), config_opts)
+            }
+            BuildSystem::Simple => {
+                self.get_simple_commands(module, &module_build_commands, &build_args)
+            }
             BuildSystem::Qmake => {
-                return box_error!("QMake build-system not implement yet");
+                self.get_qmake_commands(module, rebuild, build_args.clone(), config_opts)
             }
         };
 
-        debug_println!("build commands count: {}", commands.len());
-        full_println!("build commands: {:#?}", commands);
+        // Same order as flatpak-builder: build-commands run between build and install, which is
+        // always the last build system step, and post-install runs after install.
+        if !matches!(*build_system, BuildSystem::Simple) && !module_build_commands.is_empty() {
+            let install_command = commands.pop();
+            commands.extend(self.get_simple_commands(module, &module_build_commands, &build_args));
+            commands.extend(install_command);
+        }
+        commands.extend(self.get_simple_commands(module, &module_post_install, &build_args));
+
         Ok(commands)
     }
 
     fn get_autotools_commands(
         &self,
+        module: &Module,
         rebuild: bool,
         build_args: Vec<String>,
         config_opts: Vec<String>,
     ) -> Vec<Command> {
         // Logical cpu count.
-        let cpu_num = num_cpus::get();
+        let cpu_num = self.jobs();
 
         let mut commands: Vec<Command> = vec![];
 
@@ -376,8 +1030,9 @@ impl Manifest {
             build_args.iter().for_each(|x| _ = cmd.arg(x));
             cmd.arg(&self.repo_dir)
                 .arg("./configure")
-                .arg("--prefix=/app");
+                .arg(format!("--prefix={}", self.install_prefix(module)));
             config_opts.iter().for_each(|x| _ = cmd.arg(x));
+            cmd.current_dir(self.source_dir(module));
             commands.push(cmd);
         }
 
@@ -390,6 +1045,7 @@ impl Manifest {
             .arg("-p")
             .arg("-n")
             .arg("-s");
+        make_cmd.current_dir(self.source_dir(module));
         commands.push(make_cmd);
 
         let mut make_install_cmd = Command::new("flatpak");
@@ -401,7 +1057,7 @@ impl Manifest {
             .arg("V=0")
             .arg(format!("-j{}", cpu_num))
             .arg("install");
-
+        make_install_cmd.current_dir(self.source_dir(module));
         commands.push(make_install_cmd);
 
         commands
@@ -409,21 +1065,17 @@ impl Manifest {
 
     fn get_cmake_commands(
         &self,
+        module: &Module,
         rebuild: bool,
         build_args: Vec<String>,
         config_opts: Vec<String>,
     ) -> Vec<Command> {
         let mut commands: Vec<Command> = vec![];
-        let cmake_build_dir = BUILD_SYSTEM_BUILD_DIR;
-        let cmake_build_full_dir =
-            format!("{}/{}", self.root_dir.to_str().unwrap(), cmake_build_dir);
-
-        let mut build_args: Vec<String> = build_args;
-        build_args.push(format!("--filesystem={}", cmake_build_full_dir));
+        let cmake_build_full_dir = self.module_build_dir(module);
 
         if !rebuild {
             let mut cmd = Command::new("mkdir");
-            cmd.arg("-p").arg(cmake_build_dir);
+            cmd.arg("-p").arg(&cmake_build_full_dir);
             commands.push(cmd);
 
             let mut cmake_cmd = Command::new("flatpak");
@@ -434,11 +1086,23 @@ impl Manifest {
                 .arg("cmake")
                 .arg("-G")
                 .arg("Ninja")
-                .arg("..")
-                .arg(".")
+                .arg("-S")
+                .arg(self.source_dir(module))
+                .arg("-B")
+                .arg(&cmake_build_full_dir)
                 .arg("-DCMAKE_EXPORT_COMPILE_COMMANDS=1")
-                .arg("-DCMAKE_BUILD_TYPE=RelWithDebInfo")
-                .arg("-DCMAKE_INSTALL_PREFIX=/app");
+                .arg(format!(
+                    "-DCMAKE_INSTALL_PREFIX={}",
+                    self.install_prefix(module)
+                ));
+            if let Some(v) = Manifest::cmake_build_type(&self.build_type) {
+                if !config_opts
+                    .iter()
+                    .any(|x| x.starts_with("-DCMAKE_BUILD_TYPE"))
+                {
+                    cmake_cmd.arg(format!("-DCMAKE_BUILD_TYPE={}", v));
+                }
+            }
             config_opts.iter().for_each(|x| _ = cmake_cmd.arg(x));
             cmake_cmd.current_dir(cmake_build_full_dir.clone());
             commands.push(cmake_cmd);
@@ -466,29 +1130,42 @@ impl Manifest {
 
     fn get_meson_commands(
         &self,
+        module: &Module,
         rebuild: bool,
         build_args: Vec<String>,
         config_opts: Vec<String>,
     ) -> Vec<Command> {
-        let mut commands: Vec<Command> = vec![];
-        let meson_build_dir = BUILD_SYSTEM_BUILD_DIR;
-        let meson_build_full_dir =
-            format!("{}/{}", self.root_dir.to_str().unwrap(), meson_build_dir);
+        // Logical cpu count.
+        let cpu_num = self.jobs();
 
-        let mut build_args: Vec<String> = build_args;
-        build_args.push(format!("--filesystem={}", meson_build_full_dir));
+        let mut commands: Vec<Command> = vec![];
+        let source_dir = self.source_dir(module);
+        let meson_build_dir = self.module_build_dir(module);
 
         if !rebuild {
             let mut meson_cmd = Command::new("flatpak");
             meson_cmd.arg("build");
             build_args.iter().for_each(|x| _ = meson_cmd.arg(x));
-            meson_cmd
-                .arg(&self.repo_dir)
-                .arg("meson")
-                .arg("--prefix")
-                .arg("/app")
-                .arg(meson_build_dir);
+            meson_cmd.arg(&self.repo_dir).arg("meson").arg("setup");
+            // An already configured build directory must be reconfigured, otherwise meson refuses
+            // to run and changed config-opts are never applied.
+            if meson_build_dir
+                .join("meson-private")
+                .join("coredata.dat")
+                .exists()
+            {
+                meson_cmd.arg("--reconfigure");
+            }
+            meson_cmd.arg(format!("--prefix={}", self.install_prefix(module)));
+            if !config_opts
+                .iter()
+                .any(|x| x.starts_with("--buildtype") || x.starts_with("-Dbuildtype"))
+            {
+                meson_cmd.arg(format!("--buildtype={}", self.build_type));
+            }
             config_opts.iter().for_each(|x| _ = meson_cmd.arg(x));
+            meson_cmd.arg(&meson_build_dir).arg(&source_dir);
+            meson_cmd.current_dir(&source_dir);
             commands.push(meson_cmd);
         }
 
@@ -497,79 +1174,875 @@ impl Manifest {
         build_args.iter().for_each(|x| _ = meson_build_cmd.arg(x));
         meson_build_cmd
             .arg(&self.repo_dir)
-            .arg("ninja")
+            .arg("meson")
+            .arg("compile")
             .arg("-C")
-            .arg(meson_build_dir);
+            .arg(&meson_build_dir)
+            .arg(format!("--jobs={}", cpu_num));
+        meson_build_cmd.current_dir(&source_dir);
         commands.push(meson_build_cmd);
 
         let mut meson_install_cmd = Command::new("flatpak");
         meson_install_cmd.arg("build");
         build_args.iter().for_each(|x| _ = meson_install_cmd.arg(x));
+        // Already compiled in the previous step.
         meson_install_cmd
             .arg(&self.repo_dir)
-            .arg("ninja")
+            .arg("meson")
             .arg("install")
+            .arg("--no-rebuild")
             .arg("-C")
-            .arg(meson_build_dir);
+            .arg(&meson_build_dir);
+        meson_install_cmd.current_dir(&source_dir);
         commands.push(meson_install_cmd);
 
         commands
     }
 
-    fn get_simple_commands(
+    fn get_qmake_commands(
         &self,
-        build_commands: Vec<String>,
+        module: &Module,
+        rebuild: bool,
         build_args: Vec<String>,
+        config_opts: Vec<String>,
     ) -> Vec<Command> {
-        build_commands
-            .iter()
-            .map(|x| {
-                let mut command = Command::new("flatpak");
-                command.arg("build");
-                build_args.iter().for_each(|x| _ = command.arg(x));
-                command.arg(&self.repo_dir);
-                x.split(' ').for_each(|xx| _ = command.arg(xx));
-                command
-            })
-            .collect()
-    }
+        // Logical cpu count.
+        let cpu_num = self.jobs();
 
-    fn get_envs(&self) -> Vec<String> {
-        let mut envs = vec![];
+        let mut commands: Vec<Command> = vec![];
+        let source_dir = self.source_dir(module);
 
-        let default_path = vec!["/app/bin".to_string(), "/usr/bin".to_string()];
-        override_env!(envs, self, "PATH", default_path, prepend_path, append_path);
-        let default_ld_library_path = vec!["/app/lib".to_string()];
-        override_env!(
-            envs,
-            self,
-            "LD_LIBRARY_PATH",
-            default_ld_library_path,
-            prepend_ld_library_path,
-            append_ld_library_path
-        );
-        let default_pkg_config_path = vec![
-            "/app/lib/pkgconfig".to_string(),
-            "/app/share/pkgconfig".to_string(),
-            "/usr/lib/pkgconfig".to_string(),
-            "/usr/share/pkgconfig".to_string(),
-        ];
-        override_env!(
-            envs,
-            self,
-            "PKG_CONFIG_PATH",
-            default_pkg_config_path,
-            prepend_pkg_config_path,
-            append_pkg_config_path
-        );
+        // Same as flatpak-builder, only build out of tree when the module asks for it.
+        let qmake_build_dir = if module.builddir.unwrap_or(false) {
+            self.module_build_dir(module)
+        } else {
+            source_dir.clone()
+        };
+
+        let mut build_args: Vec<String> = build_args;
+        build_args.push(format!(
+            "--filesystem={}",
+            qmake_build_dir.to_str().unwrap()
+        ));
+
+        if !rebuild {
+            let mut cmd = Command::new("mkdir");
+            cmd.arg("-p").arg(&qmake_build_dir);
+            commands.push(cmd);
+
+            let mut qmake_cmd = Command::new("flatpak");
+            qmake_cmd.arg("build");
+            build_args.iter().for_each(|x| _ = qmake_cmd.arg(x));
+            qmake_cmd
+                .arg(&self.repo_dir)
+                .arg("qmake")
+                .arg(format!("PREFIX={}", self.install_prefix(module)));
+            config_opts.iter().for_each(|x| _ = qmake_cmd.arg(x));
+            qmake_cmd.arg(&source_dir);
+            qmake_cmd.current_dir(&qmake_build_dir);
+            commands.push(qmake_cmd);
+        }
+
+        let mut make_cmd = Command::new("flatpak");
+        make_cmd.arg("build");
+        build_args.iter().for_each(|x| _ = make_cmd.arg(x));
+        make_cmd
+            .arg(&self.repo_dir)
+            .arg("make")
+            .arg(format!("-j{}", cpu_num));
+        make_cmd.args(module.make_args.to_owned().unwrap_or_default());
+        make_cmd.current_dir(&qmake_build_dir);
+        commands.push(make_cmd);
+
+        let mut make_install_cmd = Command::new("flatpak");
+        make_install_cmd.arg("build");
+        build_args.iter().for_each(|x| _ = make_install_cmd.arg(x));
+        make_install_cmd
+            .arg(&self.repo_dir)
+            .arg("make")
+            .arg("install");
+        make_install_cmd.args(module.make_install_args.to_owned().unwrap_or_default());
+        make_install_cmd.current_dir(&qmake_build_dir);
+        commands.push(make_install_cmd);
+
+        commands
+    }
+
+    fn get_simple_commands(
+        &self,
+        module: &Module,
+        build_commands: &[String],
+        build_args: &[String],
+    ) -> Vec<Command> {
+        build_commands
+            .iter()
+            .map(|x| {
+                // flatpak-builder runs each build command through `/bin/sh -c` in the source dir.
+                let mut command = Command::new("flatpak");
+                command.arg("build");
+                build_args.iter().for_each(|x| _ = command.arg(x));
+                command.arg(&self.repo_dir).arg("/bin/sh").arg("-c").arg(x);
+                command.current_dir(self.source_dir(module));
+                command
+            })
+            .collect()
+    }
+
+    /// CMake build type of meson build type `build_type`, None for plain and custom ones.
+    fn cmake_build_type(build_type: &str) -> Option<&'static str> {
+        match build_type {
+            "debug" => Some("Debug"),
+            "debugoptimized" => Some("RelWithDebInfo"),
+            "release" => Some("Release"),
+            "minsize" => Some("MinSizeRel"),
+            _ => None,
+        }
+    }
+
+    /// CFLAGS and CXXFLAGS of `module`, cflags and cxxflags in build-options of manifest, then
+    /// module, then cflags of selected build profile for the app module, joined like
+    /// flatpak-builder does.
+    fn get_flags_envs(&self, module: &Module) -> Vec<String> {
+        let build_options = [
+            self.manifest.build_options.as_ref(),
+            module.build_options.as_ref(),
+        ];
+        let profile_cflags = self
+            .build_profile()
+            .filter(|_| self.is_app_module(module))
+            .and_then(|x| x.cflags.as_ref());
+        let join = |flags: Vec<Option<&String>>| -> String {
+            flags
+                .into_iter()
+                .flatten()
+                .map(|x| x.as_str())
+                .collect::<Vec<&str>>()
+                .join(" ")
+        };
+
+        let mut cflags: Vec<Option<&String>> = build_options
+            .iter()
+            .map(|x| x.and_then(|xx| xx.cflags.as_ref()))
+            .collect();
+        cflags.push(profile_cflags);
+        let mut cxxflags: Vec<Option<&String>> = build_options
+            .iter()
+            .map(|x| x.and_then(|xx| xx.cxxflags.as_ref()))
+            .collect();
+        cxxflags.push(profile_cflags);
+
+        [("CFLAGS", join(cflags)), ("CXXFLAGS", join(cxxflags))]
+            .into_iter()
+            .filter(|(_, x)| !x.is_empty())
+            .map(|(key, value)| format!("--env={}={}", key, value))
+            .collect()
+    }
+
+    /// Envs set by selected build profile.
+    fn get_profile_envs(&self) -> Vec<String> {
+        let profile = match self.build_profile() {
+            Some(v) => v,
+            None => return vec![],
+        };
+        profile
+            .env
+            .iter()
+            .map(|(key, value)| format!("--env={}={}", key, value))
+            .collect()
+    }
+
+    /// Envs keeping colored output of build steps, which is piped to remap paths, when printing
+    /// to a terminal.
+    fn get_color_envs(&self) -> Vec<String> {
+        if is_message_format_json() || !std::io::stdout().is_terminal() {
+            return vec![];
+        }
+        vec![
+            "--env=CLICOLOR_FORCE=1".to_string(),
+            "--env=CMAKE_COLOR_DIAGNOSTICS=ON".to_string(),
+            "--env=CARGO_TERM_COLOR=always".to_string(),
+        ]
+    }
+
+    /// Parallel jobs in build, logical cpu count unless set in config.
+    fn jobs(&self) -> usize {
+        self.config.jobs.unwrap_or_else(num_cpus::get)
+    }
+
+    /// Envs from host selected by override env prefix, see `load_envs_from_os`.
+    fn get_override_envs(&self) -> Vec<String> {
+        load_envs_from_os(self.config.override_env_prefix.as_deref())
+    }
+
+    /// Envs flatpak-builder exports to every build step.
+    fn get_builder_envs(&self, module: &Module) -> Vec<String> {
+        vec![
+            format!("--env=FLATPAK_ID={}", self.id),
+            format!("--env=FLATPAK_DEST={}", self.install_prefix(module)),
+            format!("--env=FLATPAK_ARCH={}", get_flatpak_arch()),
+            format!("--env=FLATPAK_BUILDER_N_JOBS={}", self.jobs()),
+        ]
+    }
+
+    fn get_envs(&self, module: &Module) -> Vec<String> {
+        let mut envs = vec![];
+
+        let sdk_extension_dirs = self.sdk_extension_dirs();
+
+        let mut default_path = vec!["/app/bin".to_string(), "/usr/bin".to_string()];
+        default_path.extend(sdk_extension_dirs.iter().map(|x| format!("{}/bin", x)));
+        override_env!(
+            envs,
+            self,
+            module,
+            "PATH",
+            default_path,
+            prepend_path,
+            append_path
+        );
+        let mut default_ld_library_path = vec!["/app/lib".to_string()];
+        default_ld_library_path.extend(sdk_extension_dirs.iter().map(|x| format!("{}/lib", x)));
+        override_env!(
+            envs,
+            self,
+            module,
+            "LD_LIBRARY_PATH",
+            default_ld_library_path,
+            prepend_ld_library_path,
+            append_ld_library_path
+        );
+        let default_pkg_config_path = vec![
+            "/app/lib/pkgconfig".to_string(),
+            "/app/share/pkgconfig".to_string(),
+            "/usr/lib/pkgconfig".to_string(),
+            "/usr/share/pkgconfig".to_string(),
+        ];
+        override_env!(
+            envs,
+            self,
+            module,
+            "PKG_CONFIG_PATH",
+            default_pkg_config_path,
+            prepend_pkg_config_path,
+            append_pkg_config_path
+        );
 
         envs
     }
 }
 
+/// Implement sdk extensions
+impl Manifest {
+    fn sdk_extensions(&self) -> Vec<String> {
+        self.manifest.sdk_extensions.to_owned().unwrap_or_default()
+    }
+
+    /// Mount points of sdk extensions in sandbox.
+    fn sdk_extension_dirs(&self) -> Vec<String> {
+        self.sdk_extensions()
+            .iter()
+            .map(|x| self.sdk_extension_dir(x))
+            .collect()
+    }
+
+    /// Mount point of sdk extension `id` in sandbox, from the extension point in sdk metadata.
+    ///
+    /// e.g. org.freedesktop.Sdk.Extension.rust-stable is mounted at /usr/lib/sdk/rust-stable.
+    /// Falls back to /usr/lib/sdk/$NAME when the sdk is not installed, see `sdk_extension_name`.
+    fn sdk_extension_dir(&self, id: &str) -> String {
+        for (group, values) in self.sdk_metadata() {
+            let point = match group.strip_prefix("Extension ") {
+                Some(v) => v,
+                None => continue,
+            };
+            let directory = match values.get("directory") {
+                Some(v) => format!("/usr/{}", v.trim_matches('/')),
+                None => continue,
+            };
+            if id == point {
+                return directory;
+            }
+            if values.get("subdirectories").is_some_and(|x| x == "true") {
+                if let Some(v) = id.strip_prefix(format!("{}.", point).as_str()) {
+                    return format!("{}/{}", directory, v);
+                }
+            }
+        }
+        format!(
+            "{}/{}",
+            SDK_EXTENSIONS_DIR,
+            Manifest::sdk_extension_name(id)
+        )
+    }
+
+    /// Name of sdk extension `id` without the extension point prefix, e.g. rust-stable, the last
+    /// component of `id` for other extension points.
+    fn sdk_extension_name(id: &str) -> &str {
+        id.strip_prefix(SDK_EXTENSION_POINT)
+            .and_then(|x| x.strip_prefix('.'))
+            .unwrap_or(id.rsplit('.').next().unwrap_or(id))
+    }
+
+    /// Metadata of the sdk, queried once and cached, empty if the sdk is not installed.
+    fn sdk_metadata(&self) -> &KeyFile {
+        self.sdk_metadata.get_or_init(|| {
+            let output = Command::new("flatpak")
+                .arg("info")
+                .arg("--show-metadata")
+                .arg(self.sdk_ref())
+                .output();
+            match output {
+                Ok(v) if v.status.success() => {
+                    parse_key_file(from_utf8(&v.stdout).unwrap_or_default())
+                }
+                _ => HashMap::new(),
+            }
+        })
+    }
+
+    /// Id and branch of sdk, the branch pinned in sdk, e.g. org.gnome.Sdk//46, or
+    /// runtime-version.
+    fn sdk_id_and_branch(&self) -> (&str, &str) {
+        match self.manifest.sdk.split_once("//") {
+            Some((id, branch)) if !branch.is_empty() => (id, branch),
+            Some((id, _)) => (id, self.manifest.runtime_version.as_str()),
+            None => (
+                self.manifest.sdk.as_str(),
+                self.manifest.runtime_version.as_str(),
+            ),
+        }
+    }
+
+    /// Sdk ref as `<id>//<branch>`, see `sdk_id_and_branch`.
+    fn sdk_ref(&self) -> String {
+        let (id, branch) = self.sdk_id_and_branch();
+        format!("{}//{}", id, branch)
+    }
+
+    /// Deploy location of installed ref on host, `None` if not installed.
+    fn get_ref_location(flatpak_ref: &str) -> Option<PathBuf> {
+        let output = Command::new("flatpak")
+            .arg("info")
+            .arg("--show-location")
+            .arg(flatpak_ref)
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(PathBuf::from(from_utf8(&output.stdout).ok()?.trim()))
+    }
+
+    /// Branch of sdk extensions that matches the sdk.
+    ///
+    /// Sdk extensions follow the branch of the freedesktop sdk, which may differ from
+    /// runtime-version, e.g. GNOME sdk 45 uses extensions in 23.08, so read it from sdk metadata.
+    fn sdk_extension_version(&self) -> String {
+        self.sdk_metadata()
+            .get(format!("Extension {}", SDK_EXTENSION_POINT).as_str())
+            .and_then(|x| x.get("version").cloned())
+            .unwrap_or(self.manifest.runtime_version.clone())
+    }
+
+    fn check_sdk_extensions(&self) -> Result<(), Box<dyn Error>> {
+        let extensions = self.sdk_extensions();
+        if extensions.is_empty() {
+            return Ok(());
+        }
+
+        let version = self.sdk_extension_version();
+        debug_println!("sdk extensions version: {}", version);
+
+        let missing: Vec<String> = extensions
+            .iter()
+            .map(|x| format!("{}//{}", x, version))
+            .filter(|x| Manifest::get_ref_location(x).is_none())
+            .collect();
+
+        if !missing.is_empty() {
+            return box_error!(
+                "sdk extensions not installed: {}, install with: flatpak install {}",
+                missing.join(", "),
+                missing.join(" ")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Bind mount args for sdk extensions in manifest and `extra_sdk_extensions` when running.
+    fn get_sdk_extension_mount_args(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut extensions = self.sdk_extensions();
+        extensions.extend(self.extra_sdk_extensions.to_owned());
+        extensions.sort();
+        extensions.dedup();
+        if extensions.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let version = self.sdk_extension_version();
+        let mut mount_args = vec![];
+        let mut missing = vec![];
+
+        for extension in extensions {
+            let extension_ref = format!("{}//{}", extension, version);
+            match Manifest::get_ref_location(&extension_ref) {
+                Some(v) => mount_args.push(format!(
+                    "--bind-mount={}={}",
+                    self.sdk_extension_dir(&extension),
+                    v.join("files").to_str().unwrap()
+                )),
+                None => missing.push(extension_ref),
+            }
+        }
+
+        if !missing.is_empty() {
+            return box_error!(
+                "sdk extensions needed by running not installed: {}, install with: flatpak install {}",
+                missing.join(", "),
+                missing.join(" ")
+            );
+        }
+
+        full_println!("sdk extension mount args: {:#?}", mount_args);
+        Ok(mount_args)
+    }
+
+    /// Bind mount args for installed extensions of the extension points in `add-extensions`.
+    ///
+    /// App extensions are optional, missing ones are reported but do not stop running.
+    fn get_app_extension_mount_args(&self) -> Vec<String> {
+        let mut mount_args = vec![];
+        let add_extensions = match self.manifest.add_extensions.as_ref() {
+            Some(v) => v,
+            None => return mount_args,
+        };
+
+        let installed = Manifest::get_installed_runtimes();
+
+        for (point, extension) in add_extensions {
+            let versions: Vec<String> = match (&extension.versions, &extension.version) {
+                (Some(v), _) => v.split(';').map(|x| x.to_string()).collect(),
+                (None, Some(v)) => vec![v.to_owned()],
+                (None, None) => vec![],
+            };
+            let version_matches =
+                |branch: &str| versions.is_empty() || versions.iter().any(|x| x == branch);
+            let mount_dir = format!("/app/{}", extension.directory.trim_matches('/'));
+
+            let mut found: Vec<(String, String)> = vec![];
+            if extension.subdirectories.unwrap_or(false) {
+                let prefix = format!("{}.", point);
+                installed
+                    .iter()
+                    .filter(|(id, branch)| {
+                        id.starts_with(prefix.as_str()) && version_matches(branch)
+                    })
+                    .for_each(|(id, branch)| {
+                        found.push((
+                            format!(
+                                "{}/{}",
+                                mount_dir,
+                                id.strip_prefix(prefix.as_str()).unwrap()
+                            ),
+                            format!("{}//{}", id, branch),
+                        ))
+                    });
+            } else if let Some((id, branch)) = installed
+                .iter()
+                .find(|(id, branch)| id == point && version_matches(branch))
+            {
+                found.push((mount_dir.clone(), format!("{}//{}", id, branch)));
+            }
+
+            if found.is_empty() {
+                eprintln!(
+                    "no extension installed for extension point {}, not mounted at {}",
+                    point, mount_dir
+                );
+                continue;
+            }
+
+            for (dir, extension_ref) in found {
+                match Manifest::get_ref_location(&extension_ref) {
+                    Some(v) => mount_args.push(format!(
+                        "--bind-mount={}={}",
+                        dir,
+                        v.join("files").to_str().unwrap()
+                    )),
+                    None => eprintln!("failed to get location of extension {}", extension_ref),
+                }
+            }
+        }
+
+        full_println!("app extension mount args: {:#?}", mount_args);
+        mount_args
+    }
+
+    /// Installed runtimes, including extensions, in (id, branch) pairs.
+    fn get_installed_runtimes() -> Vec<(String, String)> {
+        let output = match Command::new("flatpak")
+            .arg("list")
+            .arg("--runtime")
+            .arg("--columns=application,branch")
+            .output()
+        {
+            Ok(v) if v.status.success() => v,
+            _ => return vec![],
+        };
+
+        from_utf8(&output.stdout)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|x| x.split_once('\t'))
+            .map(|(id, branch)| (id.trim().to_string(), branch.trim().to_string()))
+            .collect()
+    }
+}
+
+/// Implement base app
+impl Manifest {
+    /// Branch of base app, same default as flatpak-builder.
+    fn base_version(&self) -> String {
+        self.manifest
+            .base_version
+            .clone()
+            .unwrap_or("master".to_string())
+    }
+
+    /// Args for `flatpak build-init` to copy base app and its extensions into build dir.
+    fn get_base_args(&self) -> Vec<String> {
+        let base = match self.manifest.base.as_ref() {
+            Some(v) => v,
+            None => return vec![],
+        };
+        let mut args = vec![
+            format!("--base={}", base),
+            format!("--base-version={}", self.base_version()),
+        ];
+        args.extend(
+            self.manifest
+                .base_extensions
+                .iter()
+                .flatten()
+                .map(|x| format!("--base-extension={}", x)),
+        );
+        args
+    }
+
+    /// Check base app and base extensions in manifest are installed.
+    fn check_base(&self) -> Result<(), Box<dyn Error>> {
+        let base = match self.manifest.base.as_ref() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let base_ref = format!(
+            "app/{}/{}/{}",
+            base,
+            get_flatpak_arch(),
+            self.base_version()
+        );
+        if Manifest::get_ref_location(&base_ref).is_none() {
+            return box_error!(
+                "base app not installed: {}, install with: flatpak install {}",
+                base_ref,
+                base_ref
+            );
+        }
+
+        let extensions = self.manifest.base_extensions.to_owned().unwrap_or_default();
+        if extensions.is_empty() {
+            return Ok(());
+        }
+
+        // Branch of a base extension is declared by its extension point in base app metadata.
+        let output = Command::new("flatpak")
+            .arg("info")
+            .arg("--show-metadata")
+            .arg(&base_ref)
+            .output()?;
+        let metadata = parse_key_file(from_utf8(&output.stdout).unwrap_or_default());
+        let missing: Vec<String> = extensions
+            .iter()
+            .map(|x| {
+                let version = metadata
+                    .iter()
+                    .find(|(group, _)| {
+                        group.strip_prefix("Extension ").is_some_and(|point| {
+                            x == point || x.starts_with(format!("{}.", point).as_str())
+                        })
+                    })
+                    .and_then(|(_, values)| values.get("version").cloned())
+                    .unwrap_or(self.base_version());
+                format!("{}//{}", x, version)
+            })
+            .filter(|x| Manifest::get_ref_location(x).is_none())
+            .collect();
+
+        if !missing.is_empty() {
+            return box_error!(
+                "base extensions not installed: {}, install with: flatpak install {}",
+                missing.join(", "),
+                missing.join(" ")
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Implement extension
+impl Manifest {
+    /// Whether the manifest builds an extension instead of an app.
+    pub fn is_extension(&self) -> bool {
+        self.manifest.build_extension.unwrap_or(false)
+    }
+
+    /// Extended app, runtime in manifest of an extension.
+    fn parent_app_ref(&self) -> String {
+        format!(
+            "app/{}/{}/{}",
+            self.manifest.runtime,
+            get_flatpak_arch(),
+            self.manifest.runtime_version
+        )
+    }
+
+    /// Metadata of the parent app, queried once and cached.
+    fn parent_app_metadata(&self) -> Result<&KeyFile, Box<dyn Error>> {
+        if let Some(v) = self.parent_app_metadata.get() {
+            return Ok(v);
+        }
+        let parent_ref = self.parent_app_ref();
+        let output = Command::new("flatpak")
+            .arg("info")
+            .arg("--show-metadata")
+            .arg(&parent_ref)
+            .output()?;
+        if !output.status.success() {
+            return box_error!(
+                "parent app not installed: {}, install with: flatpak install {}",
+                parent_ref,
+                parent_ref
+            );
+        }
+        Ok(self
+            .parent_app_metadata
+            .get_or_init(|| parse_key_file(from_utf8(&output.stdout).unwrap_or_default())))
+    }
+
+    fn get_extension_init_args(&self) -> Vec<String> {
+        if !self.is_extension() {
+            return vec![];
+        }
+        let mut args = vec!["--type=extension".to_string()];
+        if let Some(v) = self.manifest.extension_tag.as_ref() {
+            args.push(format!("--extension-tag={}", v));
+        }
+        args
+    }
+
+    /// Install prefix of `module`.
+    ///
+    /// prefix in build-options if set, otherwise the directory of the extension point in parent
+    /// app for extensions, /app for apps.
+    fn install_prefix(&self, module: &Module) -> String {
+        let build_options_prefix = [
+            module.build_options.as_ref(),
+            self.manifest.build_options.as_ref(),
+        ]
+        .iter()
+        .flatten()
+        .find_map(|x| x.prefix.clone());
+        if let Some(v) = build_options_prefix {
+            return v;
+        }
+        if !self.is_extension() {
+            return "/app".to_string();
+        }
+
+        let metadata = match self.parent_app_metadata() {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("failed to find extension prefix: {}", e);
+                return "/app".to_string();
+            }
+        };
+        for (group, values) in metadata {
+            let point = match group.strip_prefix("Extension ") {
+                Some(v) => v,
+                None => continue,
+            };
+            let directory = match values.get("directory") {
+                Some(v) => format!("/app/{}", v.trim_matches('/')),
+                None => continue,
+            };
+            if self.id == point {
+                return directory;
+            }
+            if values.get("subdirectories").is_some_and(|x| x == "true") {
+                if let Some(v) = self.id.strip_prefix(format!("{}.", point).as_str()) {
+                    return format!("{}/{}", directory, v);
+                }
+            }
+        }
+
+        eprintln!("extension point of {} not found in parent app", self.id);
+        "/app".to_string()
+    }
+
+    /// Sandbox permission args from [Context] and session bus policy of app `metadata`, so that
+    /// extensions run with the permissions of parent app.
+    fn get_context_args(metadata: &KeyFile) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(context) = metadata.get("Context") {
+            for (key, option) in [
+                ("shared", "--share"),
+                ("sockets", "--socket"),
+                ("devices", "--device"),
+                ("features", "--allow"),
+                ("filesystems", "--filesystem"),
+            ] {
+                context
+                    .get(key)
+                    .iter()
+                    .flat_map(|x| x.split(';'))
+                    // Negated entries only revoke permissions from the runtime, skip them.
+                    .filter(|x| !x.is_empty() && !x.starts_with('!'))
+                    .for_each(|x| args.push(format!("{}={}", option, x)));
+            }
+        }
+        if let Some(policy) = metadata.get("Session Bus Policy") {
+            for (name, access) in policy {
+                match access.as_str() {
+                    "talk" => args.push(format!("--talk-name={}", name)),
+                    "own" => args.push(format!("--own-name={}", name)),
+                    _ => {}
+                }
+            }
+        }
+        args
+    }
+}
+
 /// Implement run
 impl Manifest {
-    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Run the app with manifest `command`, or `command` if given, which must be installed in
+    /// `/app/bin`.
+    ///
+    /// `x-run-args` from manifest and `app_args` are passed to the command in order.
+    pub fn run(
+        &mut self,
+        command: Option<&str>,
+        app_args: &[String],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut args = vec!["build".to_string()];
+        args.extend(self.get_run_args()?);
+        args.push(self.repo_dir.to_str().unwrap().to_string());
+
+        // Extensions run in the parent app, mounted at /app by `flatpak build`, with the
+        // extension mounted at its extension point.
+        match command {
+            Some(v) => {
+                if !self.is_extension()
+                    && !self.repo_dir.join("files").join("bin").join(v).is_file()
+                {
+                    return box_error!("command {} not found in /app/bin", v);
+                }
+                args.push(format!("/app/bin/{}", v));
+            }
+            None if self.is_extension() => {
+                match self
+                    .parent_app_metadata()?
+                    .get("Application")
+                    .and_then(|x| x.get("command"))
+                {
+                    Some(v) => args.push(v.to_owned()),
+                    None => return box_error!("command not found in parent app metadata"),
+                }
+            }
+            None => args.push(self.manifest.command.to_string()),
+        }
+        args.extend(self.manifest.x_run_args.to_owned().unwrap_or_default());
+        args.extend(app_args.to_owned());
+
+        let mut command = Command::new("flatpak");
+        command.args(args);
+
+        debug_println!("start running");
+        full_println!("{:#?}", command);
+
+        if let Some(pid) = self.running_instance() {
+            return box_error!(
+                "app is already running with pid {}, stop it with `fbh stop`",
+                pid
+            );
+        }
+
+        let child = spawn_child(command.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+        // Child runs in its own process group, so its pid is also the group id to stop.
+        let pid = child.id() as i32;
+        let record = format!("{} {}\n", pid, process_start_time(pid).unwrap_or_default());
+        if let Err(e) = std::fs::write(self.run_pid_file(), record) {
+            eprintln!("failed to record running instance: {}", e);
+        }
+        // Only the instance holding the pidfile records its step, a refused run leaves no state.
+        set_state_file(self.state_file());
+        set_current_step("run");
+        // Output of the app is printed as it comes, it may run for long.
+        let status = wait_child_with_lines(child, print_output);
+        _ = std::fs::remove_file(self.run_pid_file());
+        set_current_step("");
+
+        if !status?.success() {
+            if is_cancelled() {
+                return box_error!("running cancelled");
+            }
+            return box_error!("error running command");
+        }
+
+        Ok(())
+    }
+
+    /// Record of the instance started by `run`, pid and start time of its process group leader.
+    fn run_pid_file(&self) -> PathBuf {
+        self.build_dir.join(RUN_PID_FILE)
+    }
+
+    /// Pid of the running instance started by `run`, stale records are removed.
+    pub fn running_instance(&self) -> Option<i32> {
+        let pid_file = self.run_pid_file();
+        let data = std::fs::read_to_string(&pid_file).ok()?;
+        let mut fields = data.split_whitespace();
+        let pid: Option<i32> = fields.next().and_then(|x| x.parse().ok());
+        let start_time: Option<u64> = fields.next().and_then(|x| x.parse().ok());
+
+        match pid {
+            Some(v) if v > 0 && process_start_time(v).is_some_and(|x| Some(x) == start_time) => {
+                Some(v)
+            }
+            _ => {
+                debug_println!("remove stale record {}", pid_file.to_str().unwrap());
+                _ = std::fs::remove_file(&pid_file);
+                None
+            }
+        }
+    }
+
+    /// Terminate the process tree of the instance started by `run`, returns whether one was
+    /// running.
+    pub fn stop(&self) -> bool {
+        let pid = match self.running_instance() {
+            Some(v) => v,
+            None => return false,
+        };
+        terminate_process_group(pid);
+        _ = std::fs::remove_file(self.run_pid_file());
+        true
+    }
+
+    /// Args for `flatpak build` when running the app, before the repo dir.
+    fn get_run_args(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
         let uid = Manifest::get_uid();
         let app_id = self.id.as_str();
         if self.fonts_args.is_empty() {
@@ -581,7 +2054,6 @@ impl Manifest {
         }
 
         let mut args: Vec<String> = vec![
-            "build".to_string(),
             "--with-appdir".to_string(),
             "--allow=devel".to_string(),
             format!(
@@ -591,6 +2063,9 @@ impl Manifest {
         ];
 
         args.extend(self.get_finish_args());
+        if self.is_extension() {
+            args.extend(Manifest::get_context_args(self.parent_app_metadata()?));
+        }
         args.extend([
             "--talk-name=org.freedesktop.portal.*".to_string(),
             "--talk-name=org.a11y.Bus".to_string(),
@@ -605,31 +2080,14 @@ impl Manifest {
                 .collect::<Vec<String>>(),
         );
 
-        // TODO: Check mountExtensions?
+        args.extend(self.get_sdk_extension_mount_args()?);
+        args.extend(self.get_app_extension_mount_args());
         args.push("--share=network".to_string());
 
         args.extend(self.fonts_args.to_owned());
-        args.push(self.repo_dir.to_str().unwrap().to_string());
+        args.extend(self.config.run_args.to_owned());
 
-        // TODO: Customize shellCommand
-        args.push(self.manifest.command.to_string());
-
-        let mut command = Command::new("flatpak");
-        command.args(args);
-
-        debug_println!("start running");
-        full_println!("{:#?}", command);
-
-        let output = command.output()?;
-
-        if !output.status.success() {
-            println!("{}", String::from_utf8(output.stderr).unwrap());
-            return box_error!("error running command");
-        }
-
-        println!("{}", String::from_utf8(output.stdout).unwrap());
-
-        Ok(())
+        Ok(args)
     }
 
     fn get_finish_args(&self) -> Vec<String> {
@@ -737,7 +2195,7 @@ impl Manifest {
             .arg("--method=org.a11y.Bus.GetAddress")
             .output()?;
         if !gdbus_output.status.success() {
-            println!("{}", String::from_utf8(gdbus_output.stderr).unwrap());
+            print_output(OutputStream::Stderr, &gdbus_output.stderr);
             return box_error!("failed to get a11y dbus args when running gdbus command");
         }
 
@@ -769,3 +2227,939 @@ impl Manifest {
         Ok(bus_args)
     }
 }
+
+/// Implement shell and exec
+impl Manifest {
+    /// Start an interactive shell in the build environment, or the runtime environment if
+    /// `runtime` is true, returns the exit code of shell.
+    pub fn shell(&mut self, runtime: bool) -> Result<i32, Box<dyn Error>> {
+        self.exec(runtime, &[SANDBOX_SHELL.to_string()])
+    }
+
+    /// Args of flatpak to run a command in the build environment, or the runtime environment if
+    /// `runtime` is true, the command follows.
+    pub fn get_sandbox_args(&mut self, runtime: bool) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut args = vec!["build".to_string()];
+        if runtime {
+            args.extend(self.get_run_args()?);
+        } else {
+            args.extend(self.get_build_args());
+        }
+        args.extend(self.get_override_envs());
+        args.push(self.repo_dir.to_str().unwrap().to_string());
+        Ok(args)
+    }
+
+    /// Run `command` in the build environment, or the runtime environment if `runtime` is true,
+    /// returns the exit code of `command`.
+    ///
+    /// Current directory is kept if inside `root_dir`, which has the same path in sandbox.
+    pub fn exec(&mut self, runtime: bool, command: &[String]) -> Result<i32, Box<dyn Error>> {
+        if command.is_empty() {
+            return box_error!("no command to execute");
+        }
+        if !self.is_initialized() {
+            return box_error!("build directory not initialized, run build first");
+        }
+
+        let mut args = self.get_sandbox_args(runtime)?;
+        args.extend(command.to_owned());
+
+        let current_dir = std::env::current_dir()
+            .ok()
+            .filter(|x| x.starts_with(&self.root_dir))
+            .unwrap_or(self.root_dir.clone());
+
+        let mut cmd = Command::new("flatpak");
+        cmd.args(args).current_dir(current_dir);
+
+        full_println!("exec command: {:#?}", cmd);
+
+        let status = cmd.status()?;
+        Ok(status
+            .code()
+            .or(status.signal().map(|x| 128 + x))
+            .unwrap_or(1))
+    }
+}
+
+/// Implement language server wrappers
+impl Manifest {
+    /// Link `path` in build tree from the dir keeping build trees of all manifests, e.g.
+    /// .flatpak/compile_commands.json, so that editors find it at the same path whichever
+    /// manifest is built. The link is removed if `path` does not exist.
+    fn link_from_build_trees_dir(&self, path: &Path) -> std::io::Result<()> {
+        let build_trees_dir = self.build_dir.parent().unwrap();
+        let link = build_trees_dir.join(path.file_name().unwrap());
+        if link.symlink_metadata().is_ok() {
+            std::fs::remove_file(&link)?;
+        }
+        if !path.exists() {
+            return Ok(());
+        }
+        std::os::unix::fs::symlink(path.strip_prefix(build_trees_dir).unwrap_or(path), &link)
+    }
+
+    /// Generate wrapper scripts in build dir running language servers and toolchains in the build
+    /// environment, returns paths of generated wrappers.
+    ///
+    /// Paths inside the repo files dir in arguments are translated to /app, `root_dir` has the
+    /// same path in sandbox.
+    pub fn generate_lsp_wrappers(&self) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        if !self.is_initialized() {
+            return box_error!("build directory not initialized, run build first");
+        }
+
+        let sdk_extensions: Vec<SdkExtension> = self
+            .sdk_extensions()
+            .iter()
+            .filter_map(|x| SdkExtension::from_name(Manifest::sdk_extension_name(x)))
+            .collect();
+
+        let mut build_args = self.get_build_args();
+        build_args.extend(self.get_override_envs());
+        let quoted_args = build_args
+            .iter()
+            .map(|x| shell_quote(x))
+            .collect::<Vec<String>>()
+            .join(" \\\n    ");
+        let files_dir = self.repo_dir.join("files");
+
+        let mut wrappers = vec![];
+        for (tool, extension) in LSP_WRAPPER_TOOLS.iter() {
+            let wrapper_path = self.build_dir.join(tool);
+            if let Some(v) = extension {
+                if !sdk_extensions.contains(v) {
+                    info_println!(
+                        "skip {}: provided by the {} sdk extension, which is not in manifest",
+                        tool,
+                        v
+                    );
+                    if wrapper_path.exists() {
+                        std::fs::remove_file(&wrapper_path)?;
+                        self.link_from_build_trees_dir(&wrapper_path)?;
+                    }
+                    continue;
+                }
+            }
+
+            let content = format!(
+                r#"#!/bin/sh
+# Generated by flatpak-build-helper, runs {tool} in the flatpak build environment.
+files_dir={files_dir}
+for arg do
+    shift
+    case "$arg" in
+        "$files_dir"/*) arg="/app/${{arg#"$files_dir"/}}" ;;
+    esac
+    set -- "$@" "$arg"
+done
+exec flatpak build \
+    {quoted_args} \
+    {repo_dir} {tool} "$@"
+"#,
+                files_dir = shell_quote(files_dir.to_str().unwrap()),
+                repo_dir = shell_quote(self.repo_dir.to_str().unwrap()),
+            );
+
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&wrapper_path)?;
+            file.write_all(content.as_bytes())?;
+            set_permissions(&wrapper_path, Permissions::from_mode(0o755))?;
+
+            self.link_from_build_trees_dir(&wrapper_path)?;
+            debug_println!("generated wrapper: {}", wrapper_path.to_str().unwrap());
+            wrappers.push(wrapper_path);
+        }
+
+        Ok(wrappers)
+    }
+}
+
+/// Implement compile commands
+impl Manifest {
+    /// Write a copy of compile_commands.json generated in build into build dir, with sandbox
+    /// paths rewritten to host paths, so that clangd on host can use it.
+    ///
+    /// /app is mapped to repo files dir, /usr to files dir of the deployed sdk and sdk extensions
+    /// to their deployed files dirs. Include dirs of sdk and sdk extensions are added as system
+    /// include dirs, since the compiler is the one on host then.
+    pub fn update_host_compile_commands(&self) -> Result<(), Box<dyn Error>> {
+        let compile_commands_paths: Vec<PathBuf> = self
+            .build_system_dirs()
+            .into_iter()
+            .map(|x| x.join(COMPILE_COMMANDS_FILE))
+            .filter(|x| x.is_file())
+            .collect();
+        if compile_commands_paths.is_empty() {
+            full_println!("skip updating compile commands: file not found");
+            return Ok(());
+        }
+
+        let mut path_map: Vec<(String, String)> = vec![(
+            "/app".to_string(),
+            self.repo_dir.join("files").to_str().unwrap().to_string(),
+        )];
+
+        let extension_version = self.sdk_extension_version();
+        for extension in self.sdk_extensions() {
+            if let Some(v) =
+                Manifest::get_ref_location(&format!("{}//{}", extension, extension_version))
+            {
+                path_map.push((
+                    self.sdk_extension_dir(&extension),
+                    v.join("files").to_str().unwrap().to_string(),
+                ));
+            }
+        }
+
+        match Manifest::get_ref_location(&self.sdk_ref()) {
+            Some(v) => path_map.push((
+                "/usr".to_string(),
+                v.join("files").to_str().unwrap().to_string(),
+            )),
+            None => return box_error!("sdk {} not installed", self.manifest.sdk),
+        }
+
+        full_println!("compile commands path map: {:#?}", path_map);
+
+        let system_include_dirs: Vec<String> = path_map[1..]
+            .iter()
+            .map(|(_, x)| format!("{}/include", x))
+            .filter(|x| Path::new(x).is_dir())
+            .collect();
+
+        // Entries of all in tree modules are merged, so that local libraries are covered too.
+        let mut compile_commands: Vec<serde_json::Value> = vec![];
+        for path in compile_commands_paths {
+            let data: serde_json::Value = serde_json::from_str(read_to_string(&path)?.as_str())?;
+            match data {
+                serde_json::Value::Array(v) => compile_commands.extend(v),
+                _ => return box_error!("invalid {}: not an array", path.to_str().unwrap()),
+            }
+        }
+        Manifest::rewrite_compile_commands(&mut compile_commands, &path_map, &system_include_dirs)?;
+
+        let host_compile_commands_path = self.build_dir.join(COMPILE_COMMANDS_FILE);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&host_compile_commands_path)?;
+        file.write_all(serde_json::to_string_pretty(&compile_commands)?.as_bytes())?;
+
+        self.link_from_build_trees_dir(&host_compile_commands_path)?;
+
+        debug_println!(
+            "updated host compile commands: {}",
+            host_compile_commands_path.to_str().unwrap()
+        );
+
+        Ok(())
+    }
+
+    /// Rewrite sandbox paths in `compile_commands` entries to host paths by `path_map`, and add
+    /// `system_include_dirs` with -isystem to every entry.
+    ///
+    /// Only the source, the output and paths of include options are rewritten, a sandbox path
+    /// must start the value, or follow an include option, and end at a path component, to not
+    /// touch host paths that happen to contain it, e.g. /app-data.
+    fn rewrite_compile_commands(
+        compile_commands: &mut [serde_json::Value],
+        path_map: &[(String, String)],
+        system_include_dirs: &[String],
+    ) -> Result<(), Box<dyn Error>> {
+        let paths = path_map
+            .iter()
+            .map(|(x, _)| regex::escape(x))
+            .collect::<Vec<String>>()
+            .join("|");
+        let path_re = Regex::new(format!(r#"^(?P<pre>)(?P<path>{})(?P<post>.*)"#, paths).as_str())?;
+        let option_re = Regex::new(
+            format!(
+                r#"(?P<pre>(?:^|\s)(?:{})\s*['"]?)(?P<path>{})(?P<post>[^\s'"]*)"#,
+                INCLUDE_OPTIONS.join("|"),
+                paths
+            )
+            .as_str(),
+        )?;
+        let rewrite = |re: &Regex, value: &str| -> String {
+            re.replace_all(value, |caps: &regex::Captures| {
+                let path = &caps["path"];
+                let post = &caps["post"];
+                let host_path = path_map
+                    .iter()
+                    .find(|(x, _)| x == path)
+                    .filter(|_| post.is_empty() || post.starts_with('/'))
+                    .map(|(_, x)| x.as_str())
+                    .unwrap_or(path);
+                format!("{}{}{}", &caps["pre"], host_path, post)
+            })
+            .to_string()
+        };
+
+        for entry in compile_commands.iter_mut() {
+            let entry = match entry.as_object_mut() {
+                Some(v) => v,
+                None => continue,
+            };
+            for (key, value) in entry.iter_mut() {
+                match (key.as_str(), value) {
+                    ("directory" | "file" | "output", serde_json::Value::String(v)) => {
+                        *v = rewrite(&path_re, v)
+                    }
+                    ("command", serde_json::Value::String(v)) => {
+                        *v = rewrite(&option_re, v);
+                        for dir in system_include_dirs {
+                            v.push_str(&format!(" -isystem {}", shell_quote(dir)));
+                        }
+                    }
+                    ("arguments", serde_json::Value::Array(v)) => {
+                        let mut include_value = false;
+                        for x in v.iter_mut() {
+                            if let serde_json::Value::String(xx) = x {
+                                *xx = if include_value {
+                                    rewrite(&path_re, xx)
+                                } else {
+                                    rewrite(&option_re, xx)
+                                };
+                                include_value = INCLUDE_OPTIONS.contains(&xx.as_str());
+                            }
+                        }
+                        for dir in system_include_dirs {
+                            v.push("-isystem".into());
+                            v.push(dir.as_str().into());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Project in a temporary root dir, removed on drop.
+    struct TestProject {
+        root_dir: PathBuf,
+    }
+
+    impl TestProject {
+        fn new(name: &str) -> TestProject {
+            let root_dir =
+                std::env::temp_dir().join(format!("fbh-test-{}-{}", std::process::id(), name));
+            _ = remove_dir_all(&root_dir);
+            std::fs::create_dir_all(root_dir.join("build-aux")).unwrap();
+            // Dir sources are compared with canonical paths.
+            TestProject {
+                root_dir: root_dir.canonicalize().unwrap(),
+            }
+        }
+
+        /// Manifest in build-aux, `fields` override those of a minimal app manifest.
+        fn manifest(&self, fields: serde_json::Value) -> Manifest {
+            let mut data = serde_json::json!({
+                "id": "org.example.App.Devel",
+                "runtime": "org.gnome.Platform",
+                "runtime-version": "46",
+                "sdk": "org.gnome.Sdk",
+                "command": "example",
+                "modules": [
+                    {
+                        "name": "example",
+                        "buildsystem": "meson",
+                        "sources": [{ "type": "dir", "path": ".." }],
+                    },
+                ],
+            });
+            data.as_object_mut()
+                .unwrap()
+                .extend(fields.as_object().unwrap().clone());
+            let manifest_path = self
+                .root_dir
+                .join("build-aux")
+                .join("org.example.App.Devel.json");
+            std::fs::write(&manifest_path, data.to_string()).unwrap();
+            Manifest::new(
+                self.root_dir.clone(),
+                serde_json::from_value(data).unwrap(),
+                manifest_path,
+                Config::default(),
+            )
+        }
+    }
+
+    impl Drop for TestProject {
+        fn drop(&mut self) {
+            _ = remove_dir_all(&self.root_dir);
+        }
+    }
+
+    /// Write repo metadata and init record as build-init of `manifest` would.
+    fn init(manifest: &Manifest) {
+        let arch = get_flatpak_arch();
+        let (sdk_id, sdk_branch) = manifest.sdk_id_and_branch();
+        let metadata = if manifest.is_extension() {
+            format!(
+                "[Runtime]\nname={}\nsdk={}/{}/{}\n\n[ExtensionOf]\nref={}\n",
+                manifest.id,
+                sdk_id,
+                arch,
+                sdk_branch,
+                manifest.parent_app_ref()
+            )
+        } else {
+            format!(
+                "[Application]\nname={}\nruntime={}/{}/{}\nsdk={}/{}/{}\n",
+                manifest.id,
+                manifest.manifest.runtime,
+                arch,
+                manifest.manifest.runtime_version,
+                sdk_id,
+                arch,
+                sdk_branch
+            )
+        };
+        std::fs::create_dir_all(&manifest.repo_dir).unwrap();
+        std::fs::write(manifest.repo_dir.join("metadata"), metadata).unwrap();
+        let record = serde_json::json!({
+            "sdk-extensions": manifest.sdk_extensions(),
+            "base": manifest.get_base_args(),
+        });
+        std::fs::write(manifest.init_record_file(), record.to_string()).unwrap();
+    }
+
+    #[test]
+    fn stale_init_not_initialized() {
+        let project = TestProject::new("stale-not-initialized");
+        assert_eq!(
+            project.manifest(serde_json::json!({})).stale_init_reason(),
+            None
+        );
+    }
+
+    #[test]
+    fn stale_init_unchanged() {
+        let project = TestProject::new("stale-unchanged");
+        let fields = serde_json::json!({
+            "sdk-extensions": ["org.freedesktop.Sdk.Extension.rust-stable"],
+            "base": "org.example.Base",
+        });
+        init(&project.manifest(fields.clone()));
+        assert_eq!(project.manifest(fields).stale_init_reason(), None);
+    }
+
+    #[test]
+    fn stale_init_runtime_changed() {
+        let project = TestProject::new("stale-runtime");
+        init(&project.manifest(serde_json::json!({})));
+        let reason = project
+            .manifest(serde_json::json!({ "runtime-version": "47" }))
+            .stale_init_reason()
+            .unwrap();
+        assert!(reason.starts_with("runtime changed"), "{}", reason);
+    }
+
+    #[test]
+    fn stale_init_sdk_extensions_changed() {
+        let project = TestProject::new("stale-sdk-extensions");
+        init(&project.manifest(serde_json::json!({})));
+        let reason = project
+            .manifest(serde_json::json!({
+                "sdk-extensions": ["org.freedesktop.Sdk.Extension.vala"],
+            }))
+            .stale_init_reason()
+            .unwrap();
+        assert!(reason.starts_with("sdk extensions changed"), "{}", reason);
+    }
+
+    #[test]
+    fn stale_init_base_changed() {
+        let project = TestProject::new("stale-base");
+        init(&project.manifest(serde_json::json!({ "base": "org.example.Base" })));
+        let reason = project
+            .manifest(serde_json::json!({ "base": "org.example.Other" }))
+            .stale_init_reason()
+            .unwrap();
+        assert!(reason.starts_with("base changed"), "{}", reason);
+    }
+
+    #[test]
+    fn stale_init_sdk_branch() {
+        let project = TestProject::new("stale-sdk-branch");
+        let fields = serde_json::json!({ "sdk": "org.gnome.Sdk//46", "runtime-version": "master" });
+        init(&project.manifest(fields.clone()));
+        assert_eq!(project.manifest(fields).stale_init_reason(), None);
+        let reason = project
+            .manifest(
+                serde_json::json!({ "sdk": "org.gnome.Sdk//47", "runtime-version": "master" }),
+            )
+            .stale_init_reason()
+            .unwrap();
+        assert!(reason.starts_with("sdk changed"), "{}", reason);
+    }
+
+    #[test]
+    fn stale_init_extension() {
+        let project = TestProject::new("stale-extension");
+        let fields = serde_json::json!({
+            "id": "org.example.App.Plugin",
+            "build-extension": true,
+            "runtime": "org.example.App",
+            "runtime-version": "stable",
+        });
+        init(&project.manifest(fields.clone()));
+        assert_eq!(project.manifest(fields).stale_init_reason(), None);
+        let reason = project
+            .manifest(serde_json::json!({
+                "id": "org.example.App.Plugin",
+                "build-extension": true,
+                "runtime": "org.example.App",
+                "runtime-version": "beta",
+            }))
+            .stale_init_reason()
+            .unwrap();
+        assert!(reason.starts_with("parent app changed"), "{}", reason);
+    }
+
+    #[test]
+    fn clean_removes_build_system_dirs_of_all_profiles() {
+        let project = TestProject::new("clean");
+        let manifest = project.manifest(serde_json::json!({}));
+        init(&manifest);
+        for name in ["_build", "_build-asan", "_build.modules/lib", "_builder"] {
+            std::fs::create_dir_all(manifest.build_dir.join(name)).unwrap();
+        }
+
+        manifest.clean().unwrap();
+        assert!(!manifest.repo_dir.exists());
+        let mut names: Vec<String> = manifest
+            .build_dir
+            .read_dir()
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_str().unwrap().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["_builder", INIT_RECORD_FILE]);
+    }
+
+    #[test]
+    fn remove_stale_init_keeps_other_modules() {
+        let project = TestProject::new("remove-stale-init");
+        let manifest = project.manifest(serde_json::json!({}));
+        init(&manifest);
+        let build_dir = manifest.state_dir.join("build");
+        for name in ["example-1", "example-2", "examples-1", "other-1"] {
+            std::fs::create_dir_all(build_dir.join(name)).unwrap();
+        }
+        std::os::unix::fs::symlink("example-2", build_dir.join("example")).unwrap();
+
+        manifest.remove_stale_init().unwrap();
+        assert!(!manifest.repo_dir.exists());
+        assert!(!manifest.init_record_file().exists());
+        let mut names: Vec<String> = build_dir
+            .read_dir()
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_str().unwrap().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["examples-1", "other-1"]);
+    }
+
+    #[test]
+    fn sdk_extension_names() {
+        assert_eq!(
+            Manifest::sdk_extension_name("org.freedesktop.Sdk.Extension.rust-stable"),
+            "rust-stable"
+        );
+        assert_eq!(
+            Manifest::sdk_extension_name("org.example.Sdk.Extension.tool"),
+            "tool"
+        );
+        assert_eq!(Manifest::sdk_extension_name("tool"), "tool");
+    }
+
+    fn module_names(modules: Vec<&Module>) -> Vec<&str> {
+        modules.iter().map(|x| x.name.as_str()).collect()
+    }
+
+    #[test]
+    fn modules_in_build_order() {
+        let project = TestProject::new("modules");
+        let manifest = project.manifest(serde_json::json!({
+            "modules": [
+                {
+                    "name": "a",
+                    "sources": [],
+                    "modules": [
+                        { "name": "a1", "sources": [] },
+                        { "name": "a2", "sources": [], "disabled": true },
+                    ],
+                },
+                { "name": "b", "sources": [], "disabled": true },
+                { "name": "c", "sources": [] },
+            ],
+        }));
+        assert_eq!(module_names(manifest.modules()), vec!["a1", "a", "c"]);
+    }
+
+    #[test]
+    fn module_detected_from_dir_source() {
+        let project = TestProject::new("module-detected");
+        std::fs::create_dir_all(project.root_dir.join("lib")).unwrap();
+        let manifest = project.manifest(serde_json::json!({
+            "modules": [
+                { "name": "app", "sources": [{ "type": "dir", "path": ".." }] },
+                { "name": "lib", "sources": [{ "type": "dir", "path": "../lib" }] },
+                { "name": "remote", "sources": [{ "type": "git", "url": "https://example.com" }] },
+            ],
+        }));
+        assert_eq!(manifest.module().unwrap().name, "app");
+        assert!(manifest.is_root_dir_module(manifest.module().unwrap()));
+        assert!(!manifest.is_root_dir_module(manifest.modules()[1]));
+    }
+
+    #[test]
+    fn module_defaults_to_last() {
+        let project = TestProject::new("module-last");
+        let manifest = project.manifest(serde_json::json!({
+            "modules": [
+                { "name": "a", "sources": [{ "type": "git", "url": "https://example.com" }] },
+                { "name": "b", "sources": [{ "type": "archive", "url": "https://example.com" }] },
+            ],
+        }));
+        assert_eq!(manifest.module().unwrap().name, "b");
+    }
+
+    #[test]
+    fn module_selected() {
+        let project = TestProject::new("module-selected");
+        let mut manifest = project.manifest(serde_json::json!({
+            "modules": [
+                {
+                    "name": "a",
+                    "sources": [],
+                    "modules": [{ "name": "inner", "sources": [] }],
+                },
+                { "name": "off", "sources": [], "disabled": true },
+                { "name": "b", "sources": [{ "type": "dir", "path": ".." }] },
+            ],
+        }));
+        manifest.set_module("inner").unwrap();
+        assert_eq!(manifest.module().unwrap().name, "inner");
+        assert!(manifest.set_module("off").is_err());
+        assert!(manifest.set_module("missing").is_err());
+        assert_eq!(manifest.module().unwrap().name, "inner");
+    }
+
+    #[test]
+    fn in_tree_modules_before_app() {
+        let project = TestProject::new("in-tree-modules");
+        for dir in ["lib/a", "lib/b"] {
+            std::fs::create_dir_all(project.root_dir.join(dir)).unwrap();
+        }
+        let outside = TestProject::new("in-tree-modules-outside");
+        let manifest = project.manifest(serde_json::json!({
+            "modules": [
+                { "name": "outside", "sources": [{ "type": "dir", "path": outside.root_dir }] },
+                { "name": "remote", "sources": [{ "type": "git", "url": "https://example.com" }] },
+                { "name": "a", "sources": [{ "type": "dir", "path": "../lib/a" }] },
+                {
+                    "name": "b",
+                    "sources": [
+                        { "type": "dir", "path": "../lib/b" },
+                        { "type": "dir", "path": "../lib/a" },
+                    ],
+                },
+                { "name": "app", "sources": [{ "type": "dir", "path": ".." }] },
+            ],
+        }));
+        assert!(!manifest.is_local_module(manifest.modules()[0]));
+        let modules = manifest.in_tree_modules();
+        assert_eq!(module_names(modules.clone()), vec!["a", "b", "app"]);
+        assert_eq!(
+            manifest.source_dir(modules[0]),
+            project.root_dir.join("lib").join("a")
+        );
+        assert_eq!(manifest.source_dir(modules[2]), project.root_dir);
+        assert_eq!(
+            manifest.module_build_dir(modules[0]),
+            manifest.build_dir.join("_build.modules").join("a")
+        );
+        assert_eq!(
+            manifest.module_build_dir(modules[2]),
+            manifest.build_dir.join("_build")
+        );
+    }
+
+    #[test]
+    fn in_tree_modules_stop_at_other_sources() {
+        let project = TestProject::new("in-tree-modules-stop");
+        std::fs::create_dir_all(project.root_dir.join("lib")).unwrap();
+        let manifest = project.manifest(serde_json::json!({
+            "modules": [
+                { "name": "lib", "sources": [{ "type": "dir", "path": "../lib" }] },
+                {
+                    "name": "mixed",
+                    "sources": [
+                        { "type": "dir", "path": "../lib" },
+                        { "type": "patch", "path": "fix.patch" },
+                    ],
+                },
+                { "name": "app", "sources": [{ "type": "dir", "path": ".." }] },
+            ],
+        }));
+        assert_eq!(module_names(manifest.in_tree_modules()), vec!["app"]);
+    }
+
+    fn rewrite_compile_commands(entry: serde_json::Value) -> serde_json::Value {
+        let mut compile_commands = vec![entry];
+        Manifest::rewrite_compile_commands(
+            &mut compile_commands,
+            &[
+                ("/app".to_string(), "/repo/files".to_string()),
+                ("/usr".to_string(), "/sdk/files".to_string()),
+            ],
+            &["/sdk/files/include".to_string()],
+        )
+        .unwrap();
+        compile_commands.pop().unwrap()
+    }
+
+    #[test]
+    fn rewrite_compile_commands_command() {
+        let entry = rewrite_compile_commands(serde_json::json!({
+            "directory": "/app/build",
+            "command": "/usr/bin/cc -I/app/include -I /usr/include/glib-2.0 -isystem '/usr/lib' \
+                -I/app-data/include -I/src/app/include -o main.o -c /src/app/main.c",
+            "file": "/src/app/main.c",
+            "output": "/app-data/main.o",
+        }));
+        assert_eq!(
+            entry,
+            serde_json::json!({
+                "directory": "/repo/files/build",
+                "command": "/usr/bin/cc -I/repo/files/include -I /sdk/files/include/glib-2.0 \
+                    -isystem '/sdk/files/lib' -I/app-data/include -I/src/app/include -o main.o \
+                    -c /src/app/main.c -isystem '/sdk/files/include'",
+                "file": "/src/app/main.c",
+                "output": "/app-data/main.o",
+            })
+        );
+    }
+
+    #[test]
+    fn rewrite_compile_commands_arguments() {
+        let entry = rewrite_compile_commands(serde_json::json!({
+            "directory": "/src/app/_build",
+            "arguments": [
+                "/usr/bin/cc", "-I/app/include", "-I", "/usr/include/glib-2.0", "-I",
+                "/app-data/include", "-I/usr", "-o", "/app/main.o", "-c", "/src/app/main.c",
+            ],
+            "file": "/src/app/main.c",
+        }));
+        assert_eq!(
+            entry,
+            serde_json::json!({
+                "directory": "/src/app/_build",
+                "arguments": [
+                    "/usr/bin/cc", "-I/repo/files/include", "-I", "/sdk/files/include/glib-2.0",
+                    "-I", "/app-data/include", "-I/sdk/files", "-o", "/app/main.o", "-c",
+                    "/src/app/main.c", "-isystem", "/sdk/files/include",
+                ],
+                "file": "/src/app/main.c",
+            })
+        );
+    }
+
+    #[test]
+    fn cmake_build_types() {
+        assert_eq!(Manifest::cmake_build_type("debug"), Some("Debug"));
+        assert_eq!(
+            Manifest::cmake_build_type(MESON_DEFAULT_BUILD_TYPE),
+            Some("RelWithDebInfo")
+        );
+        assert_eq!(Manifest::cmake_build_type("release"), Some("Release"));
+        assert_eq!(Manifest::cmake_build_type("minsize"), Some("MinSizeRel"));
+        assert_eq!(Manifest::cmake_build_type("plain"), None);
+    }
+
+    #[test]
+    fn flags_envs_appended() {
+        let project = TestProject::new("flags-envs");
+        let mut manifest = project.manifest(serde_json::json!({
+            "build-options": { "cflags": "-O2 -g", "cxxflags": "-O2" },
+            "modules": [
+                {
+                    "name": "example",
+                    "buildsystem": "meson",
+                    "build-options": { "cflags": "-DEXAMPLE" },
+                    "sources": [{ "type": "dir", "path": ".." }],
+                },
+            ],
+        }));
+        let module = manifest.module().unwrap();
+        assert_eq!(
+            manifest.get_flags_envs(module),
+            vec!["--env=CFLAGS=-O2 -g -DEXAMPLE", "--env=CXXFLAGS=-O2"]
+        );
+
+        manifest.config.profiles.insert(
+            "asan".to_string(),
+            toml::from_str(r#"cflags = "-O1 -fsanitize=address""#).unwrap(),
+        );
+        manifest.set_profile("asan").unwrap();
+        let module = manifest.module().unwrap();
+        assert_eq!(
+            manifest.get_flags_envs(module),
+            vec![
+                "--env=CFLAGS=-O2 -g -DEXAMPLE -O1 -fsanitize=address",
+                "--env=CXXFLAGS=-O2 -O1 -fsanitize=address",
+            ]
+        );
+    }
+
+    #[test]
+    fn configured_state_dir_shared() {
+        let project = TestProject::new("configured-dirs");
+        let manifest = project.manifest(serde_json::json!({}));
+        let config: Config = toml::from_str(
+            r#"
+            build-dir = "out"
+            state-dir = "/var/tmp/fbh-state"
+            "#,
+        )
+        .unwrap();
+        let manifest = Manifest::new(
+            project.root_dir.clone(),
+            manifest.manifest,
+            manifest.manifest_path,
+            config,
+        );
+        let build_tree = manifest.build_dir.file_name().unwrap();
+        assert!(build_tree
+            .to_str()
+            .unwrap()
+            .starts_with("org.example.App.Devel-"));
+        assert_eq!(
+            manifest.build_dir,
+            project.root_dir.join("out").join(build_tree)
+        );
+        assert_eq!(manifest.repo_dir, manifest.build_dir.join("repo"));
+        assert_eq!(manifest.state_dir, PathBuf::from("/var/tmp/fbh-state"));
+    }
+
+    /// Extension manifest `id` with parent app metadata `metadata`, as queried from flatpak.
+    fn extension_manifest(project: &TestProject, id: &str, metadata: &str) -> Manifest {
+        let manifest = project.manifest(serde_json::json!({
+            "id": id,
+            "build-extension": true,
+            "runtime": "org.example.App",
+            "runtime-version": "stable",
+        }));
+        manifest
+            .parent_app_metadata
+            .set(parse_key_file(metadata))
+            .unwrap();
+        manifest
+    }
+
+    #[test]
+    fn install_prefix_of_extension() {
+        let project = TestProject::new("install-prefix");
+        let metadata = "[Application]\n\
+            name=org.example.App\n\
+            \n\
+            [Extension org.example.App.Plugin]\n\
+            directory=plugin/\n\
+            \n\
+            [Extension org.example.App.Codecs]\n\
+            directory=lib/codecs\n\
+            subdirectories=true\n";
+
+        let manifest = extension_manifest(&project, "org.example.App.Plugin", metadata);
+        assert_eq!(
+            manifest.install_prefix(manifest.module().unwrap()),
+            "/app/plugin"
+        );
+        let manifest = extension_manifest(&project, "org.example.App.Codecs.Extra", metadata);
+        assert_eq!(
+            manifest.install_prefix(manifest.module().unwrap()),
+            "/app/lib/codecs/Extra"
+        );
+        let manifest = extension_manifest(&project, "org.example.App.Other", metadata);
+        assert_eq!(manifest.install_prefix(manifest.module().unwrap()), "/app");
+
+        let manifest = project.manifest(serde_json::json!({}));
+        assert_eq!(manifest.install_prefix(manifest.module().unwrap()), "/app");
+        let manifest = project.manifest(serde_json::json!({
+            "build-options": { "prefix": "/app/custom" },
+        }));
+        assert_eq!(
+            manifest.install_prefix(manifest.module().unwrap()),
+            "/app/custom"
+        );
+    }
+
+    #[test]
+    fn context_args_of_parent_app() {
+        let metadata = parse_key_file(
+            "[Application]\n\
+            name=org.example.App\n\
+            \n\
+            [Context]\n\
+            shared=network;ipc;\n\
+            sockets=wayland;!x11;\n\
+            filesystems=xdg-music:ro;\n\
+            \n\
+            [Session Bus Policy]\n\
+            org.example.Service=talk\n\
+            org.example.App.Owned=own\n\
+            org.example.Seen=see\n",
+        );
+        let mut args = Manifest::get_context_args(&metadata);
+        args.sort();
+        assert_eq!(
+            args,
+            vec![
+                "--filesystem=xdg-music:ro",
+                "--own-name=org.example.App.Owned",
+                "--share=ipc",
+                "--share=network",
+                "--socket=wayland",
+                "--talk-name=org.example.Service",
+            ]
+        );
+    }
+
+    #[test]
+    fn autotools_by_default_in_subdir() {
+        let project = TestProject::new("autotools");
+        let manifest = project.manifest(serde_json::json!({
+            "modules": [
+                {
+                    "name": "example",
+                    "subdir": "src",
+                    "sources": [{ "type": "dir", "path": ".." }],
+                },
+            ],
+        }));
+        let commands = manifest.setup_command(false).unwrap();
+        assert_eq!(commands.len(), 3);
+        assert!(commands[0].get_args().any(|x| x == "./configure"));
+        for command in commands {
+            assert_eq!(
+                command.get_current_dir(),
+                Some(project.root_dir.join("src").as_path())
+            );
+        }
+    }
+}
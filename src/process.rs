@@ -0,0 +1,254 @@
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{exit, Child, Command, ExitStatus, Output};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libc::{c_int, kill, sighandler_t, signal, SIGINT, SIGKILL, SIGTERM};
+use serde_json::json;
+
+use crate::constants::SIGNAL_FORWARD_TIMEOUT_MS;
+use crate::event::OutputStream;
+use crate::full_println;
+
+/// Process group of the child process currently running a step, 0 if none.
+///
+/// Each child runs in its own process group, so that signals reach the whole tree of
+/// `flatpak build` and bwrap.
+static CURRENT_CHILD: AtomicI32 = AtomicI32::new(0);
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+/// Signal received by fbh, 0 if none.
+static RECEIVED_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Name of current step, recorded in project state file when interrupted.
+static CURRENT_STEP: Mutex<String> = Mutex::new(String::new());
+static STATE_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Spawn `command` in a new process group and record it as the current child until
+/// `wait_child` or `wait_child_with_output`.
+///
+/// Like waiting, exits if a signal is received, see `install_signal_handlers`.
+pub fn spawn_child(command: &mut Command) -> std::io::Result<Child> {
+    exit_if_signalled();
+    if is_cancelled() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Interrupted,
+            "cancelled",
+        ));
+    }
+    let child = command.process_group(0).spawn()?;
+    CURRENT_CHILD.store(child.id() as i32, Ordering::SeqCst);
+    Ok(child)
+}
+
+pub fn wait_child(mut child: Child) -> std::io::Result<ExitStatus> {
+    let status = child.wait();
+    CURRENT_CHILD.store(0, Ordering::SeqCst);
+    exit_if_signalled();
+    status
+}
+
+pub fn wait_child_with_output(child: Child) -> std::io::Result<Output> {
+    let output = child.wait_with_output();
+    CURRENT_CHILD.store(0, Ordering::SeqCst);
+    exit_if_signalled();
+    output
+}
+
+/// Wait `child` with piped stdout and stderr, passing their lines to `on_line` as they come.
+pub fn wait_child_with_lines<F>(mut child: Child, on_line: F) -> std::io::Result<ExitStatus>
+where
+    F: Fn(OutputStream, &[u8]) + Sync,
+{
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    thread::scope(|s| {
+        s.spawn(|| forward_lines(stdout, |x| on_line(OutputStream::Stdout, x)));
+        s.spawn(|| forward_lines(stderr, |x| on_line(OutputStream::Stderr, x)));
+    });
+
+    wait_child(child)
+}
+
+fn forward_lines<R: Read, F: Fn(&[u8])>(reader: R, on_line: F) {
+    let mut reader = BufReader::new(reader);
+    let mut line = vec![];
+    while let Ok(size) = reader.read_until(b'\n', &mut line) {
+        if size == 0 {
+            break;
+        }
+        on_line(&line);
+        line.clear();
+    }
+}
+
+/// Cancel current operation, the running child is terminated and no more steps should start.
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+    let pgid = CURRENT_CHILD.load(Ordering::SeqCst);
+    if pgid > 0 {
+        full_println!("terminate child process group {}", pgid);
+        unsafe {
+            kill(-pgid, SIGTERM);
+        }
+    }
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+pub fn reset_cancelled() {
+    CANCELLED.store(false, Ordering::SeqCst);
+}
+
+pub fn set_current_step(step: &str) {
+    let mut lock = CURRENT_STEP.lock().unwrap();
+    *lock = step.to_owned();
+    drop(lock);
+}
+
+pub fn set_state_file(path: PathBuf) {
+    let mut lock = STATE_FILE.lock().unwrap();
+    *lock = Some(path);
+    drop(lock);
+}
+
+/// Interrupted step recorded in project state file by a previous run, if any.
+pub fn read_interrupted_step(state_file: &PathBuf) -> Option<String> {
+    let data = std::fs::read_to_string(state_file).ok()?;
+    let state: serde_json::Value = serde_json::from_str(data.as_str()).ok()?;
+    state["interrupted"]["step"].as_str().map(|x| x.to_string())
+}
+
+pub fn clear_interrupted_step(state_file: &PathBuf) {
+    if state_file.exists() {
+        _ = std::fs::remove_file(state_file);
+    }
+}
+
+/// Start time of process `pid` in clock ticks since boot, used to tell a recorded process from
+/// another one reusing its pid. None if the process does not exist.
+pub fn process_start_time(pid: i32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // Command name in the second field may contain spaces, fields after it are plain.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Terminate process group `pgid` with SIGTERM, then SIGKILL if still running after timeout.
+pub fn terminate_process_group(pgid: i32) {
+    full_println!("terminate process group {}", pgid);
+    unsafe {
+        kill(-pgid, SIGTERM);
+    }
+    let deadline = Instant::now() + Duration::from_millis(SIGNAL_FORWARD_TIMEOUT_MS);
+    while unsafe { kill(-pgid, 0) } == 0 {
+        if Instant::now() >= deadline {
+            eprintln!("process group {} not exited in time, killing", pgid);
+            unsafe {
+                kill(-pgid, SIGKILL);
+            }
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+extern "C" fn handle_signal(sig: c_int) {
+    // Only async-signal-safe operations here, the rest is done in watchdog thread.
+    CANCELLED.store(true, Ordering::SeqCst);
+    let first = RECEIVED_SIGNAL.swap(sig, Ordering::SeqCst) == 0;
+    let pgid = CURRENT_CHILD.load(Ordering::SeqCst);
+    if pgid > 0 {
+        unsafe {
+            kill(-pgid, if first { sig } else { SIGKILL });
+        }
+    }
+    if !first {
+        unsafe {
+            libc::_exit(128 + sig);
+        }
+    }
+}
+
+/// Forward SIGINT and SIGTERM to the running child process group.
+///
+/// The child is killed if still running after timeout. Once it exits, the interrupted step is
+/// recorded in project state file and fbh exits with 128 + signal number, from the thread
+/// waiting the child, or from the watchdog thread. A second signal exits immediately.
+pub fn install_signal_handlers() {
+    unsafe {
+        signal(
+            SIGINT,
+            handle_signal as extern "C" fn(c_int) as sighandler_t,
+        );
+        signal(
+            SIGTERM,
+            handle_signal as extern "C" fn(c_int) as sighandler_t,
+        );
+    }
+
+    thread::spawn(|| {
+        let sig = loop {
+            let v = RECEIVED_SIGNAL.load(Ordering::SeqCst);
+            if v != 0 {
+                break v;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+
+        let deadline = Instant::now() + Duration::from_millis(SIGNAL_FORWARD_TIMEOUT_MS);
+        while CURRENT_CHILD.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        let pgid = CURRENT_CHILD.load(Ordering::SeqCst);
+        if pgid > 0 {
+            eprintln!("child not exited in time, killing");
+            unsafe {
+                kill(-pgid, SIGKILL);
+            }
+        }
+
+        exit_interrupted(sig);
+    });
+}
+
+fn exit_if_signalled() {
+    let sig = RECEIVED_SIGNAL.load(Ordering::SeqCst);
+    if sig != 0 {
+        exit_interrupted(sig);
+    }
+}
+
+/// Record the interrupted step in project state file and exit with 128 + `sig`.
+fn exit_interrupted(sig: c_int) -> ! {
+    // Only the first caller records and exits, others wait here until the process exits.
+    static EXITING: Mutex<()> = Mutex::new(());
+    let _lock = EXITING.lock().unwrap();
+
+    let step = CURRENT_STEP.lock().unwrap().clone();
+    if step.is_empty() {
+        exit(128 + sig);
+    }
+    if let Some(state_file) = STATE_FILE.lock().unwrap().as_ref() {
+        let state = json!({
+            "interrupted": {
+                "step": step,
+                "signal": sig,
+                "time": SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|x| x.as_secs())
+                    .unwrap_or_default(),
+            }
+        });
+        _ = std::fs::write(state_file, state.to_string());
+    }
+
+    eprintln!("interrupted during {}", step);
+    exit(128 + sig);
+}
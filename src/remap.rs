@@ -0,0 +1,213 @@
+use std::borrow::Cow;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+
+use crate::event::OutputStream;
+use crate::process::{spawn_child, wait_child_with_lines};
+
+lazy_static! {
+    // Color escape sequences right before a path, e.g. in gcc diagnostics.
+    static ref COLOR_SUFFIX_RE: Regex = Regex::new(r"(?:\[[0-9;]*[mK])+$").unwrap();
+}
+
+/// Rewrite paths seen inside the build sandbox in build output to real host paths.
+///
+/// * /run/build/$MODULE is the source dir of in tree module $MODULE, e.g. the app module.
+/// * /run/build/$MODULE is the kept build dir of dependency $MODULE in flatpak-builder state dir.
+/// * /app is the repo files dir.
+pub struct PathRemapper {
+    re: Regex,
+    source_dirs: Vec<(Vec<u8>, Vec<u8>)>,
+    state_build_dir: Vec<u8>,
+    files_dir: Vec<u8>,
+}
+
+impl PathRemapper {
+    /// `source_dirs` are names and source dirs of in tree modules.
+    pub fn new(
+        source_dirs: Vec<(&str, PathBuf)>,
+        state_dir: &Path,
+        repo_dir: &Path,
+    ) -> PathRemapper {
+        PathRemapper {
+            re: Regex::new(r#"/run/build/(?P<module>[^/\s:'"()\[\],;]+)|(?P<app>/app)"#).unwrap(),
+            source_dirs: source_dirs
+                .into_iter()
+                .map(|(x, xx)| (x.as_bytes().to_vec(), xx.as_os_str().as_bytes().to_vec()))
+                .collect(),
+            state_build_dir: state_dir
+                .join("build")
+                .to_str()
+                .unwrap()
+                .as_bytes()
+                .to_vec(),
+            files_dir: repo_dir.join("files").to_str().unwrap().as_bytes().to_vec(),
+        }
+    }
+
+    /// Remap paths in `line`, everything else is kept byte-identical.
+    pub fn remap<'a>(&self, line: &'a [u8]) -> Cow<'a, [u8]> {
+        let mut ret = vec![];
+        let mut last = 0;
+        for caps in self.re.captures_iter(line) {
+            let path = caps.get(0).unwrap();
+            // Sandbox path must be a whole word so that host paths containing it are untouched.
+            if !is_path_start(&line[..path.start()]) || !is_path_end(&line[path.end()..]) {
+                continue;
+            }
+            ret.extend(&line[last..path.start()]);
+            match caps.name("module") {
+                Some(v) => match self.source_dirs.iter().find(|(x, _)| x == v.as_bytes()) {
+                    Some((_, source_dir)) => ret.extend(source_dir),
+                    None => {
+                        ret.extend(&self.state_build_dir);
+                        ret.push(b'/');
+                        ret.extend(v.as_bytes());
+                    }
+                },
+                None => ret.extend(&self.files_dir),
+            }
+            last = path.end();
+        }
+        if last == 0 {
+            return Cow::Borrowed(line);
+        }
+        ret.extend(&line[last..]);
+        Cow::Owned(ret)
+    }
+
+    /// Run `command` to finish, with stdout and stderr remapped line by line and passed to
+    /// `on_line`.
+    pub fn run<F>(&self, command: &mut Command, on_line: F) -> std::io::Result<ExitStatus>
+    where
+        F: Fn(OutputStream, &[u8]) + Sync,
+    {
+        let child = spawn_child(command.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+        wait_child_with_lines(child, |stream, line| on_line(stream, &self.remap(line)))
+    }
+}
+
+/// Whether a path may start after `prefix`: at line start, after a delimiter, or after a -I or
+/// -L option.
+fn is_path_start(prefix: &[u8]) -> bool {
+    let prefix = match COLOR_SUFFIX_RE.find(prefix) {
+        Some(v) => &prefix[..v.start()],
+        None => prefix,
+    };
+    let prefix = [b"-I", b"-L"]
+        .iter()
+        .find_map(|x| prefix.strip_suffix(*x))
+        .unwrap_or(prefix);
+    match prefix.last() {
+        Some(v) => v.is_ascii_whitespace() || b"'\"`([=,:".contains(v),
+        None => true,
+    }
+}
+
+/// Whether a path may end before `suffix`: at line end, before a path separator or a delimiter.
+fn is_path_end(suffix: &[u8]) -> bool {
+    match suffix.first() {
+        Some(v) => v.is_ascii_whitespace() || b"/:'\"`)],;\x1b".contains(v),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remap(line: &str) -> String {
+        let remapper = PathRemapper::new(
+            vec![
+                ("example", PathBuf::from("/src/example")),
+                ("libfoo", PathBuf::from("/src/example/lib")),
+            ],
+            Path::new("/build/state"),
+            Path::new("/build/repo"),
+        );
+        String::from_utf8(remapper.remap(line.as_bytes()).to_vec()).unwrap()
+    }
+
+    #[test]
+    fn remap_app_module() {
+        assert_eq!(
+            remap("/run/build/example/src/main.c:3:5: error: expected ';'"),
+            "/src/example/src/main.c:3:5: error: expected ';'"
+        );
+    }
+
+    #[test]
+    fn remap_in_tree_module() {
+        assert_eq!(
+            remap("/run/build/libfoo/foo.c:1:1: warning: unused"),
+            "/src/example/lib/foo.c:1:1: warning: unused"
+        );
+    }
+
+    #[test]
+    fn remap_dependency_module() {
+        assert_eq!(
+            remap("In file included from /run/build/glib/glib/glib.h:30,"),
+            "In file included from /build/state/build/glib/glib/glib.h:30,"
+        );
+    }
+
+    #[test]
+    fn remap_app() {
+        assert_eq!(
+            remap("Installing foo to /app/bin"),
+            "Installing foo to /build/repo/files/bin"
+        );
+        assert_eq!(remap("prefix: /app"), "prefix: /build/repo/files");
+    }
+
+    #[test]
+    fn remap_adjacent_paths() {
+        assert_eq!(
+            remap("/app /app/lib"),
+            "/build/repo/files /build/repo/files/lib"
+        );
+        assert_eq!(
+            remap("PATH=/app/bin:/app:/usr/bin"),
+            "PATH=/build/repo/files/bin:/build/repo/files:/usr/bin"
+        );
+    }
+
+    #[test]
+    fn remap_options() {
+        assert_eq!(
+            remap("cc -I/app/include -L/app/lib -o main"),
+            "cc -I/build/repo/files/include -L/build/repo/files/lib -o main"
+        );
+        assert_eq!(remap("cc -X/app/include"), "cc -X/app/include");
+    }
+
+    #[test]
+    fn remap_quoted_and_colored() {
+        assert_eq!(
+            remap("'/app/bin' (\"/run/build/example\")"),
+            "'/build/repo/files/bin' (\"/src/example\")"
+        );
+        assert_eq!(
+            remap("\x1b[01m\x1b[K/run/build/example/a.c:1:1:\x1b[m\x1b[K"),
+            "\x1b[01m\x1b[K/src/example/a.c:1:1:\x1b[m\x1b[K"
+        );
+    }
+
+    #[test]
+    fn remap_keeps_host_paths() {
+        for line in [
+            "/home/user/app/main.c",
+            "/run/build-aux/app",
+            "/application/bin",
+            "/opt/run/build/example",
+            "no paths here",
+        ] {
+            assert_eq!(remap(line), line);
+        }
+    }
+}
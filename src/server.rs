@@ -0,0 +1,247 @@
+use std::error::Error;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+use serde_json::{json, Value};
+
+use crate::event::set_progress_request_id;
+use crate::flatpak::parse::ParseOptions;
+use crate::manifest::Manifest;
+use crate::process::{cancel, is_cancelled, reset_cancelled};
+use crate::{box_error, debug_println};
+
+// Error codes defined by JSON-RPC 2.0.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INTERNAL_ERROR: i64 = -32603;
+// Server defined error codes.
+const SERVER_BUSY: i64 = -32000;
+const REQUEST_CANCELLED: i64 = -32800;
+
+/// Id and method of the request running a long operation, only one at a time.
+static RUNNING_REQUEST: Mutex<Option<(Value, String)>> = Mutex::new(None);
+static OPERATION_THREAD: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// Serve JSON-RPC 2.0 requests, one json message per line on stdin, until stdin closed.
+///
+/// Long operations (build, rebuild, run) run in background and report events as `progress`
+/// notifications, see "Server" in README for methods.
+pub fn serve() -> Result<(), Box<dyn Error>> {
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Value>(line.as_str()) {
+            Ok(v) => handle_request(v),
+            Err(e) => send_error(Some(Value::Null), PARSE_ERROR, e.to_string()),
+        }
+    }
+
+    debug_println!("stdin closed, stop serving");
+    cancel();
+    if let Some(v) = OPERATION_THREAD.lock().unwrap().take() {
+        _ = v.join();
+    }
+    Ok(())
+}
+
+fn handle_request(request: Value) {
+    // Batches are not supported.
+    if !request.is_object() {
+        send_error(
+            Some(Value::Null),
+            INVALID_REQUEST,
+            "request is not an object".to_string(),
+        );
+        return;
+    }
+
+    // Requests without id are notifications, never responded.
+    let id = request.get("id").cloned();
+    let method = match request.get("method").and_then(|x| x.as_str()) {
+        Some(v) => v.to_string(),
+        None => {
+            // Invalid requests are responded even without id.
+            send_error(
+                id.or(Some(Value::Null)),
+                INVALID_REQUEST,
+                "method not found in request".to_string(),
+            );
+            return;
+        }
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+    debug_println!("request {}: {:?}", method, id);
+
+    match method.as_str() {
+        "$/cancelRequest" => {
+            let running = RUNNING_REQUEST.lock().unwrap();
+            if running.as_ref().map(|(x, _)| x) == params.get("id") {
+                cancel();
+            }
+        }
+        "build" | "rebuild" | "run" => start_operation(id, method, params),
+        "stop" => {
+            let running = RUNNING_REQUEST.lock().unwrap();
+            let stopped = running.as_ref().is_some_and(|(_, x)| x == "run");
+            if stopped {
+                cancel();
+            }
+            drop(running);
+            send_result(id, json!({ "stopped": stopped }));
+        }
+        "manifest/resolve" | "status" | "clean" | "shell/env" => {
+            match handle_query(method.as_str(), &params) {
+                Ok(v) => send_result(id, v),
+                Err(e) => send_error(id, INTERNAL_ERROR, e.to_string()),
+            }
+        }
+        _ => send_error(id, METHOD_NOT_FOUND, format!("unknown method {}", method)),
+    }
+}
+
+fn start_operation(id: Option<Value>, method: String, params: Value) {
+    let id = match id {
+        Some(v) => v,
+        None => return,
+    };
+
+    let mut running = RUNNING_REQUEST.lock().unwrap();
+    if running.is_some() {
+        send_error(
+            Some(id),
+            SERVER_BUSY,
+            "another operation is running".to_string(),
+        );
+        return;
+    }
+    *running = Some((id.clone(), method.clone()));
+    drop(running);
+
+    reset_cancelled();
+    set_progress_request_id(Some(id.clone()));
+
+    let handle = thread::spawn(move || {
+        let result = run_operation(method.as_str(), &params).map_err(|x| x.to_string());
+        set_progress_request_id(None);
+        *RUNNING_REQUEST.lock().unwrap() = None;
+
+        match result {
+            Ok(_) => send_result(Some(id), Value::Null),
+            Err(e) if is_cancelled() => send_error(Some(id), REQUEST_CANCELLED, e),
+            Err(e) => send_error(Some(id), INTERNAL_ERROR, e),
+        }
+    });
+    *OPERATION_THREAD.lock().unwrap() = Some(handle);
+}
+
+fn run_operation(method: &str, params: &Value) -> Result<(), Box<dyn Error>> {
+    let mut schema = load_manifest(params)?;
+    match method {
+        "build" => schema.build_project(false),
+        "rebuild" => schema.build_project(true),
+        "run" => {
+            let app_args: Vec<String> = params
+                .get("args")
+                .and_then(|x| x.as_array())
+                .map(|x| {
+                    x.iter()
+                        .filter_map(|xx| xx.as_str().map(|xxx| xxx.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            schema.build_project(false)?;
+            schema.run(params.get("command").and_then(|x| x.as_str()), &app_args)
+        }
+        _ => box_error!("unknown operation {}", method),
+    }
+}
+
+fn handle_query(method: &str, params: &Value) -> Result<Value, Box<dyn Error>> {
+    let mut schema = load_manifest(params)?;
+    match method {
+        "manifest/resolve" => Ok(json!({
+            "root_dir": schema.root_dir,
+            "manifest_path": schema.manifest_path,
+            "id": schema.id,
+            "command": schema.manifest.command,
+            "module": schema.module().map(|x| x.name.as_str()),
+            "repo_dir": schema.repo_dir,
+            "build_dir": schema.build_dir,
+            "state_dir": schema.state_dir,
+        })),
+        "status" => Ok(json!({
+            "initialized": schema.is_initialized(),
+            "built": schema
+                .repo_dir
+                .join("files")
+                .join("bin")
+                .join(&schema.manifest.command)
+                .is_file(),
+            "running": RUNNING_REQUEST.lock().unwrap().as_ref().map(|(_, x)| x.clone()),
+        })),
+        "clean" => {
+            if RUNNING_REQUEST.lock().unwrap().is_some() {
+                return box_error!("can not clean while another operation is running");
+            }
+            schema.clean()?;
+            Ok(Value::Null)
+        }
+        "shell/env" => {
+            let runtime = params
+                .get("runtime")
+                .and_then(|x| x.as_bool())
+                .unwrap_or(false);
+            Ok(json!({
+                "program": "flatpak",
+                "args": schema.get_sandbox_args(runtime)?,
+                "cwd": schema.root_dir,
+            }))
+        }
+        _ => box_error!("unknown query {}", method),
+    }
+}
+
+fn load_manifest(params: &Value) -> Result<Manifest, Box<dyn Error>> {
+    let get_string = |name: &str| {
+        params
+            .get(name)
+            .and_then(|x| x.as_str())
+            .map(|x| x.to_string())
+    };
+    ParseOptions {
+        root_dir: get_string("root_dir").map(PathBuf::from),
+        manifest: get_string("manifest").map(PathBuf::from),
+        module: get_string("module"),
+        profile: get_string("profile"),
+        build_type: get_string("build_type"),
+    }
+    .parse()
+}
+
+fn send(message: Value) {
+    let mut stdout = std::io::stdout().lock();
+    _ = writeln!(stdout, "{}", message);
+    _ = stdout.flush();
+}
+
+fn send_result(id: Option<Value>, result: Value) {
+    if let Some(id) = id {
+        send(json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+    }
+}
+
+fn send_error(id: Option<Value>, code: i64, message: String) {
+    if let Some(id) = id {
+        send(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }));
+    }
+}
@@ -7,6 +7,19 @@ macro_rules! box_error {
     ($($arg:tt)*) => {Err(Box::<dyn Error>::from(format!($($arg)*)))};
 }
 
+/// Print human readable message, to stderr in json message format so that stdout only has
+/// events, and when serving, only JSON-RPC messages.
+#[macro_export]
+macro_rules! info_println {
+    ($($arg:tt)*) => {
+        if $crate::event::is_message_format_json() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    }
+}
+
 // Log level:
 // 0 default
 // 1 more log
@@ -16,7 +29,7 @@ macro_rules! debug_println {
     ($($arg:tt)*) => {
         match std::env::var("FBH_LOG") {
             std::result::Result::Ok(v) => match v.as_str() {
-                "1" | "2" | "full" => println!($($arg)*),
+                "1" | "2" | "full" => $crate::info_println!($($arg)*),
                 _ => {},
             }
             std::result::Result::Err(_) => {},
@@ -29,7 +42,7 @@ macro_rules! full_println {
     ($($arg:tt)*) => {
         match std::env::var("FBH_LOG") {
             std::result::Result::Ok(v) => match v.as_str() {
-                "2" | "full" => println!($($arg)*) ,
+                "2" | "full" => $crate::info_println!($($arg)*),
                 _ => {},
             }
             std::result::Result::Err(_) => {},
@@ -64,6 +77,58 @@ pub fn get_user_fonts_dir() -> Vec<PathBuf> {
         dirs::home_dir().unwrap().join("fonts"),
     ]
 }
+
+/// Current architecture in flatpak naming, e.g. x86_64, aarch64, i386.
+pub fn get_flatpak_arch() -> &'static str {
+    match env::consts::ARCH {
+        "x86" => "i386",
+        v => v,
+    }
+}
+
+/// Groups of key-values in GKeyFile format, e.g. flatpak metadata.
+pub type KeyFile = HashMap<String, HashMap<String, String>>;
+
+/// Parse GKeyFile format data, e.g. flatpak metadata, into groups of key-values.
+///
+/// Comments and lines outside any group are ignored.
+pub fn parse_key_file(data: &str) -> KeyFile {
+    let mut groups: KeyFile = HashMap::new();
+    let mut current_group: Option<String> = None;
+
+    for line in data.lines().map(|x| x.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            let name = line[1..line.len() - 1].to_string();
+            groups.entry(name.clone()).or_default();
+            current_group = Some(name);
+            continue;
+        }
+        if let (Some(group), Some((key, value))) = (&current_group, line.split_once('=')) {
+            groups
+                .get_mut(group)
+                .unwrap()
+                .insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+
+    groups
+}
+
+/// 64-bit FNV-1a hash of `data`, stable across builds and platforms unlike `DefaultHasher`.
+pub fn fnv1a_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, x| {
+        (hash ^ *x as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Quote `value` as a single word for POSIX shell.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
 pub fn get_host_envs<'a, T: AsRef<[&'a str]> + Sized>(env_name_list: T) -> HashMap<String, String> {
     env_name_list
         .as_ref()
@@ -72,3 +137,55 @@ pub fn get_host_envs<'a, T: AsRef<[&'a str]> + Sized>(env_name_list: T) -> HashM
         .filter(|(_, x)| !x.is_empty())
         .collect::<HashMap<String, String>>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_key_file_groups() {
+        let groups = parse_key_file(
+            "key=outside\n\
+             [Application]\n\
+             name=org.example.App\n\
+             # comment\n\
+             runtime = org.gnome.Platform/x86_64/46\n\
+             \n\
+             [Extension org.example.App.Plugin]\n\
+             directory=plugins\n\
+             subdirectories=true\n",
+        );
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups["Application"]["name"], "org.example.App");
+        assert_eq!(
+            groups["Application"]["runtime"],
+            "org.gnome.Platform/x86_64/46"
+        );
+        assert_eq!(
+            groups["Extension org.example.App.Plugin"]["directory"],
+            "plugins"
+        );
+        assert!(!groups["Application"].contains_key("key"));
+    }
+
+    #[test]
+    fn parse_key_file_value_with_equals() {
+        let groups = parse_key_file("[Context]\nenv=A=1\n");
+        assert_eq!(groups["Context"]["env"], "A=1");
+    }
+
+    #[test]
+    fn shell_quote_words() {
+        assert_eq!(shell_quote("abc"), "'abc'");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn fnv1a_hash_known_values() {
+        assert_eq!(fnv1a_hash(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a_hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a_hash(b"foobar"), 0x85944171f73967e8);
+    }
+}
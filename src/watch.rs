@@ -0,0 +1,301 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::ffi::{CString, OsStr};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use libc::{
+    c_int, inotify_add_watch, inotify_event, inotify_init1, poll, pollfd, read, IN_CLOEXEC,
+    IN_CLOSE_WRITE, IN_CREATE, IN_DELETE, IN_ISDIR, IN_MOVED_FROM, IN_MOVED_TO, IN_NONBLOCK,
+    POLLIN,
+};
+
+use crate::constants::{BUILD_SYSTEM_BUILD_DIR, WATCH_DEBOUNCE_MS};
+use crate::flatpak::parse::ParseOptions;
+use crate::process::{cancel, reset_cancelled};
+use crate::{debug_println, full_println, info_println};
+
+const WATCH_MASK: u32 = IN_CLOSE_WRITE | IN_CREATE | IN_DELETE | IN_MOVED_FROM | IN_MOVED_TO;
+
+/// Watch changes in `root_dir` recursively with inotify.
+///
+/// .git, .flatpak, build system build dirs of all profiles and in tree modules, `ignored_dirs` and
+/// paths ignored by git are not watched.
+struct Watcher {
+    root_dir: PathBuf,
+    ignored_dirs: Vec<PathBuf>,
+    fd: c_int,
+    dirs: HashMap<c_int, PathBuf>,
+}
+
+impl Watcher {
+    fn new(root_dir: &Path, ignored_dirs: Vec<PathBuf>) -> std::io::Result<Watcher> {
+        let fd = unsafe { inotify_init1(IN_NONBLOCK | IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut watcher = Watcher {
+            root_dir: root_dir.to_path_buf(),
+            ignored_dirs,
+            fd,
+            dirs: HashMap::new(),
+        };
+        watcher.add_dir(root_dir)?;
+        Ok(watcher)
+    }
+
+    /// Watch `dir` and its sub dirs that are not ignored.
+    fn add_dir(&mut self, dir: &Path) -> std::io::Result<()> {
+        let mut sub_dirs = vec![];
+        self.collect_sub_dirs(dir, &mut sub_dirs)?;
+        // Dirs in git ignored dirs are not always reported by git, skip them by prefix.
+        let ignored = self.ignored(&sub_dirs);
+        sub_dirs.retain(|x| !ignored.iter().any(|xx| x.starts_with(xx)));
+
+        for dir in std::iter::once(dir.to_path_buf()).chain(sub_dirs) {
+            let c_path = CString::new(dir.as_os_str().as_bytes()).unwrap();
+            let wd = unsafe { inotify_add_watch(self.fd, c_path.as_ptr(), WATCH_MASK) };
+            if wd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            full_println!("watching {}", dir.to_str().unwrap());
+            self.dirs.insert(wd, dir);
+        }
+        Ok(())
+    }
+
+    /// Collect sub dirs of `dir` recursively into `ret`, except excluded ones, see `excluded`.
+    fn collect_sub_dirs(&self, dir: &Path, ret: &mut Vec<PathBuf>) -> std::io::Result<()> {
+        for sub_dir in dir
+            .read_dir()?
+            .filter_map(|x| x.ok())
+            .filter(|x| x.file_type().is_ok_and(|xx| xx.is_dir()))
+            .map(|x| x.path())
+            .filter(|x| !self.excluded(x))
+        {
+            self.collect_sub_dirs(&sub_dir, ret)?;
+            ret.push(sub_dir);
+        }
+        Ok(())
+    }
+
+    /// Whether `path` is in .git, .flatpak, build system build dirs or `ignored_dirs`.
+    fn excluded(&self, path: &Path) -> bool {
+        self.ignored_dirs.iter().any(|x| path.starts_with(x))
+            || path.strip_prefix(&self.root_dir).is_ok_and(|x| {
+                x.components().any(|c| {
+                    let name = c.as_os_str().to_str().unwrap_or_default();
+                    [".git", ".flatpak", BUILD_SYSTEM_BUILD_DIR].contains(&name)
+                        || name.starts_with(&format!("{}-", BUILD_SYSTEM_BUILD_DIR))
+                        || name.starts_with(&format!("{}.", BUILD_SYSTEM_BUILD_DIR))
+                })
+            })
+    }
+
+    /// Paths in `paths` that should not trigger rebuild, excluded or ignored by git.
+    fn ignored(&self, paths: &[PathBuf]) -> HashSet<PathBuf> {
+        let mut ignored: HashSet<PathBuf> =
+            paths.iter().filter(|x| self.excluded(x)).cloned().collect();
+
+        let rest: Vec<&PathBuf> = paths.iter().filter(|x| !ignored.contains(*x)).collect();
+        if rest.is_empty() {
+            return ignored;
+        }
+
+        // One git process for all paths, exits with error when not in a git repository, then
+        // nothing is ignored by git.
+        let mut input = vec![];
+        for path in rest {
+            input.extend(path.as_os_str().as_bytes());
+            input.push(0);
+        }
+        let child = Command::new("git")
+            .arg("-C")
+            .arg(&self.root_dir)
+            .arg("check-ignore")
+            .arg("--stdin")
+            .arg("-z")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        if let Ok(mut child) = child {
+            // Written in another thread, git may fill stdout before reading all input.
+            let mut stdin = child.stdin.take().unwrap();
+            let writer = thread::spawn(move || _ = stdin.write_all(&input));
+            if let Ok(output) = child.wait_with_output() {
+                output
+                    .stdout
+                    .split(|x| *x == 0)
+                    .filter(|x| !x.is_empty())
+                    .map(|x| self.root_dir.join(OsStr::from_bytes(x)))
+                    .for_each(|x| _ = ignored.insert(x));
+            }
+            _ = writer.join();
+        }
+
+        ignored
+    }
+
+    /// Block until changes happen, returns changed paths after no more changes in `debounce`.
+    fn wait_changes(&mut self, debounce: Duration) -> std::io::Result<Vec<PathBuf>> {
+        let mut changed = vec![];
+        let mut timeout: c_int = -1;
+        loop {
+            let mut fds = pollfd {
+                fd: self.fd,
+                events: POLLIN,
+                revents: 0,
+            };
+            let ret = unsafe { poll(&mut fds, 1, timeout) };
+            if ret < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            if ret == 0 {
+                break;
+            }
+            changed.extend(self.read_events()?);
+            timeout = debounce.as_millis() as c_int;
+        }
+
+        let ignored = self.ignored(&changed);
+        changed.retain(|x| !ignored.contains(x));
+        changed.sort();
+        changed.dedup();
+        Ok(changed)
+    }
+
+    fn read_events(&mut self) -> std::io::Result<Vec<PathBuf>> {
+        let mut changed = vec![];
+        let mut buffer = [0u8; 4096];
+        loop {
+            let size = unsafe { read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+            if size < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::WouldBlock {
+                    break;
+                }
+                return Err(err);
+            }
+
+            let mut offset = 0;
+            while offset < size as usize {
+                let event: inotify_event =
+                    unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+                let name_start = offset + std::mem::size_of::<inotify_event>();
+                let name_end = name_start + event.len as usize;
+                let name: Vec<u8> = buffer[name_start..name_end]
+                    .iter()
+                    .take_while(|x| **x != 0)
+                    .copied()
+                    .collect();
+                offset = name_end;
+
+                let dir = match self.dirs.get(&event.wd) {
+                    Some(v) => v.clone(),
+                    None => continue,
+                };
+                let path = dir.join(OsStr::from_bytes(&name));
+                if event.mask & IN_ISDIR != 0
+                    && event.mask & (IN_CREATE | IN_MOVED_TO) != 0
+                    && self.ignored(std::slice::from_ref(&path)).is_empty()
+                {
+                    _ = self.add_dir(&path);
+                }
+                changed.push(path);
+            }
+        }
+        Ok(changed)
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+fn launch(options: ParseOptions) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let result = options.parse().and_then(|mut x| x.run(None, &[]));
+        if let Err(e) = result {
+            eprintln!("app exited: {}", e);
+        }
+    })
+}
+
+fn stop(app: Option<JoinHandle<()>>) {
+    if let Some(v) = app {
+        if !v.is_finished() {
+            debug_println!("stopping app");
+            cancel();
+        }
+        _ = v.join();
+        reset_cancelled();
+    }
+}
+
+/// Build and run, then rebuild and relaunch on every change in project root dir.
+///
+/// Build failures are reported and wait for next change.
+pub fn watch(options: ParseOptions) -> Result<(), Box<dyn Error>> {
+    // Watched dirs are compared with ignored dirs by prefix, both are canonical.
+    let root_dir = match options.root_dir.as_ref() {
+        Some(v) => v.to_owned(),
+        None => std::env::current_dir()?,
+    }
+    .canonicalize()?;
+    // Build outputs may be configured inside root dir, changes in them must not trigger rebuild.
+    let ignored_dirs = match options.parse() {
+        // Parent of build dir keeps build trees of all manifests.
+        Ok(v) => {
+            let mut dirs = vec![
+                v.build_dir.parent().unwrap().to_path_buf(),
+                v.repo_dir.clone(),
+                v.state_dir.clone(),
+            ];
+            dirs.extend(v.build_system_dirs());
+            dirs.into_iter()
+                .map(|x| x.canonicalize().unwrap_or(x))
+                .collect()
+        }
+        Err(_) => vec![],
+    };
+    let mut watcher = Watcher::new(&root_dir, ignored_dirs)?;
+    let mut app: Option<JoinHandle<()>> = None;
+    let mut configured = false;
+
+    loop {
+        let result = options.parse().and_then(|x| x.build_project(configured));
+
+        match result {
+            Ok(_) => {
+                configured = true;
+                info_println!("build succeeded, launching");
+                app = Some(launch(options.clone()));
+            }
+            Err(e) => eprintln!("build failed: {}, waiting for changes", e),
+        }
+
+        let changed = loop {
+            let v = watcher.wait_changes(Duration::from_millis(WATCH_DEBOUNCE_MS))?;
+            if !v.is_empty() {
+                break v;
+            }
+        };
+        debug_println!("changed: {:#?}", changed);
+        info_println!("{} files changed, rebuilding", changed.len());
+
+        stop(app.take());
+    }
+}